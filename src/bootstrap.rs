@@ -0,0 +1,136 @@
+use hickory_proto::op::{Message, OpCode, Query};
+use hickory_proto::rr::{Name, RData, RecordType};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::IpPreference;
+
+/// 解析结果最短缓存时间
+const MIN_TTL: Duration = Duration::from_secs(30);
+
+/// 解析结果最长缓存时间
+const MAX_TTL: Duration = Duration::from_secs(3600);
+
+/// 上游服务器域名的解析函数（DoH / DoQ 客户端每次新建连接时调用，结果经 `BootstrapCache` 按 TTL 缓存）
+///
+/// 返回 None 表示未配置 bootstrap 或 bootstrap 解析失败，由调用方使用系统 DNS
+pub type ResolveHost = Arc<dyn Fn() -> Pin<Box<dyn Future<Output = Result<Option<Vec<IpAddr>>>> + Send>> + Send + Sync>;
+
+/// Bootstrap 解析结果缓存（主机名 -> IP 列表）
+///
+/// 同时缓存 A 与 AAAA 结果，读取时按地址族偏好排序/过滤；
+/// 缓存时间取记录中最小的 TTL，并限制在 30 秒到 1 小时之间
+pub struct BootstrapCache {
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+struct CacheEntry {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+impl BootstrapCache {
+    pub fn new() -> Self {
+        Self { entries: Mutex::new(HashMap::new()) }
+    }
+
+    /// 获取未过期的解析结果
+    pub fn get(&self, host: &str) -> Option<Vec<IpAddr>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(host) {
+            Some(entry) if entry.expires > Instant::now() => Some(entry.addrs.clone()),
+            Some(_) => {
+                entries.remove(host);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// 写入解析结果
+    pub fn insert(&self, host: &str, addrs: Vec<IpAddr>, ttl: Duration) {
+        let expires = Instant::now() + ttl.clamp(MIN_TTL, MAX_TTL);
+        self.entries.lock().unwrap().insert(host.to_string(), CacheEntry { addrs, expires });
+    }
+}
+
+/// 构造 bootstrap 查询（A 或 AAAA），域名格式错误时返回 None
+pub fn build_query(host: &str, record_type: RecordType) -> Option<Message> {
+    let name = Name::from_str(&format!("{}.", host.trim_end_matches('.'))).ok()?;
+    let mut request = Message::new();
+    request.set_id(rand::random());
+    request.set_op_code(OpCode::Query);
+    request.set_recursion_desired(true);
+    request.add_query(Query::query(name, record_type));
+    Some(request)
+}
+
+/// 提取响应中的 A / AAAA 地址，并返回其中最小的 TTL
+pub fn extract_addrs(response: &Message) -> (Vec<IpAddr>, Option<Duration>) {
+    let mut addrs = Vec::new();
+    let mut min_ttl: Option<u32> = None;
+    for record in response.answers() {
+        let addr = match record.data() {
+            Some(RData::A(ipv4)) => IpAddr::V4(ipv4.0),
+            Some(RData::AAAA(ipv6)) => IpAddr::V6(ipv6.0),
+            _ => continue,
+        };
+        addrs.push(addr);
+        min_ttl = Some(min_ttl.map_or(record.ttl(), |ttl| ttl.min(record.ttl())));
+    }
+    (addrs, min_ttl.map(|ttl| Duration::from_secs(ttl as u64)))
+}
+
+/// 按地址族偏好排序（同一地址族内保持原顺序），`*_only` 时过滤掉另一地址族
+pub fn apply_preference(mut addrs: Vec<IpAddr>, preference: IpPreference) -> Vec<IpAddr> {
+    match preference {
+        IpPreference::Ipv4First => addrs.sort_by_key(|addr| addr.is_ipv6()),
+        IpPreference::Ipv6First => addrs.sort_by_key(|addr| addr.is_ipv4()),
+        IpPreference::Ipv4Only => addrs.retain(IpAddr::is_ipv4),
+        IpPreference::Ipv6Only => addrs.retain(IpAddr::is_ipv6),
+    }
+    addrs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(list: &[&str]) -> Vec<IpAddr> {
+        list.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_apply_preference() {
+        let mixed = addrs(&["2001:db8::1", "192.0.2.1", "2001:db8::2", "192.0.2.2"]);
+
+        assert_eq!(
+            apply_preference(mixed.clone(), IpPreference::Ipv4First),
+            addrs(&["192.0.2.1", "192.0.2.2", "2001:db8::1", "2001:db8::2"])
+        );
+        assert_eq!(
+            apply_preference(mixed.clone(), IpPreference::Ipv6First),
+            addrs(&["2001:db8::1", "2001:db8::2", "192.0.2.1", "192.0.2.2"])
+        );
+        assert_eq!(apply_preference(mixed.clone(), IpPreference::Ipv4Only), addrs(&["192.0.2.1", "192.0.2.2"]));
+        assert_eq!(apply_preference(mixed, IpPreference::Ipv6Only), addrs(&["2001:db8::1", "2001:db8::2"]));
+    }
+
+    #[test]
+    fn test_cache_expiry() {
+        let cache = BootstrapCache::new();
+        cache.insert("dns.example", addrs(&["192.0.2.1"]), Duration::from_secs(0));
+        // TTL 低于下限时按最短缓存时间保存
+        assert_eq!(cache.get("dns.example"), Some(addrs(&["192.0.2.1"])));
+
+        cache.entries.lock().unwrap().get_mut("dns.example").unwrap().expires = Instant::now();
+        assert_eq!(cache.get("dns.example"), None);
+        assert!(cache.entries.lock().unwrap().is_empty());
+    }
+}
@@ -1,6 +1,13 @@
-use hickory_proto::op::Message;
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use hickory_proto::op::{Edns, Message, Query};
+use hickory_proto::rr::{DNSClass, Name, Record, RecordType};
+use std::collections::hash_map::DefaultHasher;
+use std::cmp::{Ordering as CmpOrdering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::fs::{self, File};
 use std::io::Write;
@@ -9,7 +16,175 @@ use tracing::{debug, info, warn};
 use anyhow::Result;
 use indexmap::IndexMap;
 
-use crate::config::{CacheConfig, CacheType, Config};
+use crate::config::{CacheConfig, CacheType, Config, EvictionPolicy, PrefetchConfig, ServeStaleConfig};
+use crate::eviction::BoundedMap;
+
+/// 缓存文件首行（带版本号）；无此行或版本不同的文件为旧格式，不含完整响应，只用于冷启动预热
+const DOMAIN_CACHE_FILE_HEADER: &str = "# creskyDNS domain cache v3";
+
+/// v2 缓存文件首行（含查询类型等缓存键字段，不含完整响应）
+const DOMAIN_CACHE_FILE_HEADER_V2: &str = "# creskyDNS domain cache v2";
+
+/// 预取调度器检查缓存的间隔
+const PREFETCH_SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 每个分片至少容纳的条目数；缓存容量较小时自动减少分片数
+const MIN_SHARD_SIZE: usize = 256;
+
+/// 按键哈希分片加锁的存储
+///
+/// 不同分片的读写互不阻塞，同一分片的读取之间也不互相阻塞
+struct Shards<T> {
+    shards: Box<[RwLock<T>]>,
+}
+
+impl<T> Shards<T> {
+    fn new(count: usize, mut make: impl FnMut() -> T) -> Self {
+        Self { shards: (0..count.max(1)).map(|_| RwLock::new(make())).collect() }
+    }
+
+    /// 键所在的分片
+    fn shard<Q: Hash + ?Sized>(&self, key: &Q) -> &RwLock<T> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &RwLock<T>> {
+        self.shards.iter()
+    }
+
+    fn len(&self) -> usize {
+        self.shards.len()
+    }
+}
+
+/// Domain Cache 的键：查询名（小写）、查询类型、查询类别与 DO/CD 位
+///
+/// 同一域名不同类型的查询（A / AAAA / MX / HTTPS 等）及是否请求 DNSSEC 记录的查询分别缓存
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    /// 查询名（小写）
+    pub qname: String,
+    pub qtype: RecordType,
+    pub qclass: DNSClass,
+    /// EDNS DO 位（请求 DNSSEC 记录）
+    pub dnssec_ok: bool,
+    /// CD 位（禁用 DNSSEC 校验）
+    pub checking_disabled: bool,
+}
+
+impl CacheKey {
+    /// 由查询名与类型创建键（IN 类别，不带 DO/CD 位）
+    #[cfg(test)]
+    pub fn new(qname: &str, qtype: RecordType) -> Self {
+        Self {
+            qname: qname.to_lowercase(),
+            qtype,
+            qclass: DNSClass::IN,
+            dnssec_ok: false,
+            checking_disabled: false,
+        }
+    }
+
+    /// 由请求的第一个问题及 DO/CD 位创建键
+    pub fn from_request(request: &Message) -> Option<Self> {
+        let query = request.queries().first()?;
+        Some(Self {
+            qname: query.name().to_lowercase().to_utf8(),
+            qtype: query.query_type(),
+            qclass: query.query_class(),
+            dnssec_ok: request.extensions().as_ref().is_some_and(|edns| edns.dnssec_ok()),
+            checking_disabled: request.checking_disabled(),
+        })
+    }
+
+    /// 构造与该键对应的查询请求（用于预热）
+    pub fn to_request(&self) -> Result<Message> {
+        let mut name = Name::from_utf8(&self.qname)?;
+        name.set_fqdn(true);
+        let mut query = Query::query(name, self.qtype);
+        query.set_query_class(self.qclass);
+
+        let mut request = Message::new();
+        request.set_id(rand::random());
+        request.set_recursion_desired(true);
+        request.set_checking_disabled(self.checking_disabled);
+        request.add_query(query);
+        if self.dnssec_ok {
+            let mut edns = Edns::new();
+            edns.set_dnssec_ok(true);
+            request.set_edns(edns);
+        }
+        Ok(request)
+    }
+
+    /// 标志位的文本形式（缓存文件使用）："-"、"DO"、"CD" 或 "DO,CD"
+    fn flags(&self) -> &'static str {
+        match (self.dnssec_ok, self.checking_disabled) {
+            (false, false) => "-",
+            (true, false) => "DO",
+            (false, true) => "CD",
+            (true, true) => "DO,CD",
+        }
+    }
+
+    /// 从缓存文件的 qname、qtype、qclass、flags 字段解析
+    fn parse(qname: &str, qtype: &str, qclass: &str, flags: &str) -> Option<Self> {
+        let flags: Vec<&str> = flags.split(',').filter(|flag| *flag != "-").collect();
+        if flags.iter().any(|flag| *flag != "DO" && *flag != "CD") {
+            return None;
+        }
+        Some(Self {
+            qname: qname.to_lowercase(),
+            qtype: qtype.parse().ok()?,
+            qclass: qclass.parse().ok()?,
+            dnssec_ok: flags.contains(&"DO"),
+            checking_disabled: flags.contains(&"CD"),
+        })
+    }
+}
+
+impl fmt::Display for CacheKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.qname, self.qtype, self.qclass)?;
+        match self.flags() {
+            "-" => Ok(()),
+            flags => write!(f, " [{}]", flags),
+        }
+    }
+}
+
+/// 命中计数（在读锁下递增；复制记录时复制当前计数）
+#[derive(Debug, Default)]
+pub struct HitCounter(AtomicU64);
+
+impl HitCounter {
+    /// 递增并返回递增后的计数
+    fn increment(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Clone for HitCounter {
+    fn clone(&self) -> Self {
+        Self(AtomicU64::new(self.get()))
+    }
+}
+
+/// 过期记录刷新失败后的暂停截止时间（写入后的毫秒数，0 表示未暂停；在读锁下设置）
+#[derive(Debug, Default)]
+pub struct RefreshHold(AtomicU64);
+
+impl Clone for RefreshHold {
+    fn clone(&self) -> Self {
+        Self(AtomicU64::new(self.0.load(Ordering::Relaxed)))
+    }
+}
 
 /// DNS 缓存记录
 #[derive(Clone, Debug)]
@@ -18,18 +193,52 @@ pub struct CachedDnsRecord {
     pub cache_id: String,
     /// 匹配到的域名（用于链接到 rule.cache）
     pub matched_domain: String,
-    /// 查询的域名
-    pub domain: String,
+    /// 缓存键（查询名、类型、类别、DO/CD 位）
+    pub key: CacheKey,
     /// 上游服务器名称（从 rule.cache 复制）
     pub upstream: String,
     /// 原始 TTL
     pub original_ttl: u64,
+    /// 写入时间点（用于计算记录的剩余 TTL）
+    pub stored_at: Instant,
     /// 过期时间点
     pub expire_at: Instant,
     /// 记录时间戳（用于导出）
     pub timestamp: u64,
     /// 缓存的 DNS 响应消息
     pub message: Message,
+    /// 写入后的命中次数（用于预取）
+    pub hits: HitCounter,
+    /// 过期后刷新失败的暂停截止时间（serve-stale）
+    pub refresh_hold: RefreshHold,
+}
+
+/// 预取队列条目：命中次数达到 `min_hits` 时加入，按预取时间排序
+#[derive(Debug)]
+struct PrefetchEntry {
+    /// 预取时间点（剩余 TTL 降到 `threshold` 比例时）
+    due: Instant,
+    key: CacheKey,
+}
+
+impl PartialEq for PrefetchEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.due == other.due
+    }
+}
+
+impl Eq for PrefetchEntry {}
+
+impl PartialOrd for PrefetchEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PrefetchEntry {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.due.cmp(&other.due)
+    }
 }
 
 impl CachedDnsRecord {
@@ -47,13 +256,26 @@ impl CachedDnsRecord {
             (self.expire_at - now).as_secs()
         }
     }
+
+    /// 过期时间的 Unix 时间戳（秒，用于导出）
+    fn expires_timestamp(&self) -> u64 {
+        self.timestamp + (self.expire_at - self.stored_at).as_secs()
+    }
+}
+
+/// Domain Cache 查询结果
+pub enum CacheHit {
+    /// 未过期的记录（TTL 为剩余时间）
+    Fresh(Message),
+    /// 已过期但仍在 serve-stale 允许范围内的记录（TTL 为 `stale_ttl`）
+    Stale(Message),
 }
 
 /// Domain Cache（DNS 缓存）
 #[derive(Clone)]
 pub struct DomainCache {
-    /// 缓存数据（domain -> record）
-    cache: Arc<RwLock<HashMap<String, CachedDnsRecord>>>,
+    /// 缓存数据（key -> record，按键哈希分片，各分片容量受限并按淘汰策略淘汰）
+    cache: Arc<Shards<BoundedMap<CacheKey, CachedDnsRecord>>>,
     /// 缓存 ID
     cache_id: String,
     /// 最大缓存条目数
@@ -62,8 +284,21 @@ pub struct DomainCache {
     min_ttl: Option<u64>,
     /// 最大 TTL（秒）
     max_ttl: Option<u64>,
+    /// 否定应答最小 TTL（秒）
+    negative_min_ttl: Option<u64>,
+    /// 否定应答最大 TTL（秒，未配置时使用 max_ttl）
+    negative_max_ttl: Option<u64>,
     /// 缓存输出文件路径
     output_path: Option<String>,
+    /// 过期缓存应答配置（未启用时为 None）
+    serve_stale: Option<ServeStaleConfig>,
+    /// 热点记录预取配置（未启用时为 None）
+    prefetch: Option<PrefetchConfig>,
+    /// 待预取的热点记录（按预取时间排序，最多 `max_size` 条）
+    prefetch_queue: Arc<Mutex<BinaryHeap<Reverse<PrefetchEntry>>>>,
+    /// 旧格式缓存文件中的条目（key, match_domain, upstream, cache_id）：不含完整响应，
+    /// 不参与应答，只在冷启动验证时加入预热列表
+    legacy_warm_up: Arc<Mutex<Vec<(CacheKey, String, String, String)>>>,
 }
 
 impl DomainCache {
@@ -74,218 +309,364 @@ impl DomainCache {
             cache_id, max_size, min_ttl, max_ttl
         );
         Self {
-            cache: Arc::new(RwLock::new(HashMap::new())),
+            cache: Arc::new(Self::new_shards(max_size, 1, EvictionPolicy::default())),
             cache_id,
             max_size,
             min_ttl,
             max_ttl,
+            negative_min_ttl: None,
+            negative_max_ttl: None,
             output_path: None,
+            serve_stale: None,
+            prefetch: None,
+            prefetch_queue: Arc::default(),
+            legacy_warm_up: Arc::default(),
         }
     }
     
     /// 从配置创建 Domain Cache
     pub fn from_config(config: &CacheConfig, cache_id: String) -> Self {
-        let cache = Arc::new(RwLock::new(HashMap::new()));
         let size = config.size.unwrap_or(10000); // 默认 10000
+        let cache = Self::new_shards(size, config.shards, config.eviction);
         
         // 如果配置了输出文件且启用了冷启动，尝试加载
+        let mut legacy_warm_up = Vec::new();
         if let Some(ref output_path) = config.output {
             if config.cold_start.as_ref().map_or(false, |cs| cs.enabled) {
-                if let Err(e) = Self::load_from_file(output_path, &cache, &cache_id) {
-                    warn!("加载域名缓存文件 {} 失败: {}, 将从空缓存开始", output_path, e);
+                match Self::load_from_file(output_path, &cache, &cache_id) {
+                    Ok(entries) => legacy_warm_up = entries,
+                    Err(e) => warn!("加载域名缓存文件 {} 失败: {}, 将从空缓存开始", output_path, e),
                 }
             }
         }
         
         info!(
-            "创建 Domain Cache '{}': size={}, min_ttl={:?}, max_ttl={:?}, output={:?}",
-            cache_id, size, config.min_ttl, config.max_ttl, config.output
+            "创建 Domain Cache '{}': size={}, shards={}, eviction={:?}, min_ttl={:?}, max_ttl={:?}, negative_min_ttl={:?}, negative_max_ttl={:?}, output={:?}",
+            cache_id, size, cache.len(), config.eviction, config.min_ttl, config.max_ttl,
+            config.negative_min_ttl, config.negative_max_ttl, config.output
         );
         
         Self {
-            cache,
+            cache: Arc::new(cache),
             cache_id,
             max_size: size,
             min_ttl: config.min_ttl,
             max_ttl: config.max_ttl,
+            negative_min_ttl: config.negative_min_ttl,
+            negative_max_ttl: config.negative_max_ttl,
             output_path: config.output.clone(),
+            serve_stale: config.serve_stale.clone().filter(|stale| stale.enabled),
+            prefetch: config.prefetch.clone().filter(|prefetch| prefetch.enabled),
+            prefetch_queue: Arc::default(),
+            legacy_warm_up: Arc::new(Mutex::new(legacy_warm_up)),
         }
     }
     
+    /// 过期缓存应答配置（未启用时为 None）
+    pub fn serve_stale(&self) -> Option<&ServeStaleConfig> {
+        self.serve_stale.as_ref()
+    }
+
+    /// 过期记录刷新失败后，在 `duration` 内暂停刷新（`duration` 为 0 时解除暂停）
+    pub fn hold_stale_refresh(&self, key: &CacheKey, duration: Duration) {
+        if let Some(record) = self.cache.shard(key).read().unwrap().peek(key) {
+            let until = if duration.is_zero() {
+                0
+            } else {
+                (Instant::now() + duration).saturating_duration_since(record.stored_at).as_millis().max(1) as u64
+            };
+            record.refresh_hold.0.store(until, Ordering::Relaxed);
+        }
+    }
+
+    /// 记录是否处于刷新失败后的暂停期
+    pub fn stale_refresh_held(&self, key: &CacheKey) -> bool {
+        self.cache.shard(key).read().unwrap().peek(key).is_some_and(|record| {
+            let until = record.refresh_hold.0.load(Ordering::Relaxed);
+            until > 0 && record.stored_at.elapsed().as_millis() < until as u128
+        })
+    }
+
+    /// 记录是否可以删除：已过期，且超出 serve-stale 允许的过期时间
+    fn is_discardable(&self, record: &CachedDnsRecord) -> bool {
+        let max_stale = self.serve_stale.as_ref().map_or(0, |stale| stale.max_stale);
+        Instant::now() >= record.expire_at + Duration::from_secs(max_stale)
+    }
+
+    /// 创建分片存储，总容量按分片数均分（每个分片至少 `MIN_SHARD_SIZE` 条）
+    fn new_shards(size: usize, shards: usize, eviction: EvictionPolicy) -> Shards<BoundedMap<CacheKey, CachedDnsRecord>> {
+        let count = shards.min(size / MIN_SHARD_SIZE).max(1);
+        Shards::new(count, || BoundedMap::new(size.div_ceil(count), eviction))
+    }
+
     /// 从文件加载缓存
-    fn load_from_file(path: &str, cache: &Arc<RwLock<HashMap<String, CachedDnsRecord>>>, _cache_id: &str) -> Result<()> {
+    ///
+    /// 按文件中的完整响应与绝对过期时间恢复，已过期的条目直接丢弃；
+    /// 旧格式（无版本标记或 v2）文件不含完整响应，无法用于应答，其条目作为预热列表返回
+    /// （key, match_domain, upstream, cache_id）
+    fn load_from_file(
+        path: &str,
+        cache: &Shards<BoundedMap<CacheKey, CachedDnsRecord>>,
+        _cache_id: &str,
+    ) -> Result<Vec<(CacheKey, String, String, String)>> {
+        use base64::engine::general_purpose::STANDARD;
+        use base64::Engine;
+
         if !Path::new(path).exists() {
-            return Ok(());
+            return Ok(Vec::new());
         }
         
         let content = fs::read_to_string(path)?;
         let mut loaded = 0;
+        let mut expired = 0;
         let now = Instant::now();
         let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        let mut lines = content.lines().filter(|line| !line.trim().is_empty()).peekable();
+        match lines.peek().map(|line| line.trim()) {
+            Some(DOMAIN_CACHE_FILE_HEADER) => {
+                lines.next();
+            }
+            header => {
+                let v2 = header == Some(DOMAIN_CACHE_FILE_HEADER_V2);
+                if v2 {
+                    lines.next();
+                }
+                let warm_up = Self::parse_legacy_lines(lines, v2);
+                info!("域名缓存文件 {} 为旧格式（不含完整响应），{} 条记录只用于预热，下次导出时写为新格式", path, warm_up.len());
+                return Ok(warm_up);
+            }
+        }
         
-        for line in content.lines() {
-            if line.trim().is_empty() {
+        for line in lines {
+            // 格式: |cache ID|match domain|upstream|qname|qtype|qclass|flags|stored|expires|message|IP(及其它信息)|
+            let fields = line.trim().trim_start_matches('|').trim_end_matches('|');
+            let parsed = match fields.splitn(11, '|').collect::<Vec<_>>()[..] {
+                [cache_id, matched_domain, upstream, qname, qtype, qclass, flags, stored, expires, message, _] => {
+                    CacheKey::parse(qname, qtype, qclass, flags)
+                        .zip(stored.parse::<u64>().ok())
+                        .zip(expires.parse::<u64>().ok())
+                        .zip(STANDARD.decode(message).ok().and_then(|wire| crate::dns::parse_dns(&wire).ok()))
+                        .map(|(((key, stored), expires), message)| (cache_id, matched_domain, upstream, key, stored, expires, message))
+                }
+                _ => None,
+            };
+            let Some((cache_id, matched_domain, upstream, key, stored, expires, message)) = parsed else {
+                debug!("跳过无效的域名缓存行: {}", line);
                 continue;
-            }
-            
-            // 格式: |cache ID|match domain|upstream|qname|ttl|IP(及其它信息)|
-            let parts: Vec<&str> = line.split('|').filter(|s| !s.is_empty()).collect();
-            if parts.len() != 6 {
+            };
+            if expires <= timestamp {
+                expired += 1;
                 continue;
             }
-            
-            let qname = parts[3].to_string();
-            let ttl: u64 = parts[4].parse().unwrap_or(0);
-            
-            // 创建简单的 DNS 消息（冷启动时只保存 IP 信息，不完整重建 Message）
-            // 实际查询时会重新获取完整记录
-            let message = Message::new();
-            // TODO: 解析 IP 信息并重建 DNS 响应
-            
+
+            // 按墙钟时间换算为当前进程的时间点，剩余 TTL 与导出前保持一致
+            let stored_at = now.checked_sub(Duration::from_secs(timestamp.saturating_sub(stored))).unwrap_or(now);
             let record = CachedDnsRecord {
-                cache_id: parts[0].to_string(),
-                matched_domain: parts[1].to_string(),
-                domain: qname.clone(),
-                upstream: parts[2].to_string(),
-                original_ttl: ttl,
-                expire_at: now + Duration::from_secs(ttl),
-                timestamp,
+                cache_id: cache_id.to_string(),
+                matched_domain: matched_domain.to_string(),
+                key: key.clone(),
+                upstream: upstream.to_string(),
+                original_ttl: expires.saturating_sub(stored),
+                stored_at,
+                expire_at: now + Duration::from_secs(expires - timestamp),
+                timestamp: stored,
                 message,
+                hits: HitCounter::default(),
+                refresh_hold: RefreshHold::default(),
             };
             
-            cache.write().unwrap().insert(qname, record);
+            cache.shard(&key).write().unwrap().insert(key, record);
             loaded += 1;
         }
         
-        info!("从文件 {} 加载了 {} 条域名缓存", path, loaded);
-        Ok(())
+        info!("从文件 {} 加载了 {} 条域名缓存（丢弃 {} 条已过期条目）", path, loaded, expired);
+        Ok(Vec::new())
+    }
+
+    /// 解析旧格式缓存文件的条目（只取缓存键、匹配域名、上游与缓存 ID）
+    ///
+    /// v2 格式: |cache ID|match domain|upstream|qname|qtype|qclass|flags|ttl|IP(及其它信息)|
+    /// 更早的格式: |cache ID|match domain|upstream|qname|ttl|IP(及其它信息)|（按 A/IN 记录迁移）
+    fn parse_legacy_lines<'a>(lines: impl Iterator<Item = &'a str>, v2: bool) -> Vec<(CacheKey, String, String, String)> {
+        let mut entries = Vec::new();
+        for line in lines {
+            let fields = line.trim().trim_start_matches('|').trim_end_matches('|');
+            let parsed = if v2 {
+                match fields.splitn(9, '|').collect::<Vec<_>>()[..] {
+                    [cache_id, matched_domain, upstream, qname, qtype, qclass, flags, _, _] => {
+                        CacheKey::parse(qname, qtype, qclass, flags).map(|key| (key, matched_domain, upstream, cache_id))
+                    }
+                    _ => None,
+                }
+            } else {
+                match fields.splitn(6, '|').collect::<Vec<_>>()[..] {
+                    [cache_id, matched_domain, upstream, qname, _, _] => {
+                        CacheKey::parse(qname, "A", "IN", "-").map(|key| (key, matched_domain, upstream, cache_id))
+                    }
+                    _ => None,
+                }
+            };
+            match parsed {
+                Some((key, matched_domain, upstream, cache_id)) => {
+                    entries.push((key, matched_domain.to_string(), upstream.to_string(), cache_id.to_string()));
+                }
+                None => debug!("跳过无效的域名缓存行: {}", line),
+            }
+        }
+        entries
     }
 
     /// 查询缓存
-    pub fn get(&self, domain: &str) -> Option<Message> {
-        let cache = self.cache.read().unwrap();
-        if let Some(record) = cache.get(domain) {
+    pub fn get(&self, key: &CacheKey) -> Option<Message> {
+        let cache = self.cache.shard(key).read().unwrap();
+        if cache.record_access(key) {
+            let record = cache.peek(key)?;
             if record.is_expired() {
-                debug!("Domain Cache '{}': 域名 {} 缓存已过期", self.cache_id, domain);
-                drop(cache);
-                // 删除过期记录
-                self.remove(domain);
+                // 过期记录由定期清理删除
+                debug!("Domain Cache '{}': {} 缓存已过期", self.cache_id, key);
                 return None;
             }
             debug!(
-                "Domain Cache '{}': 命中域名 {} (剩余 TTL: {}s)",
+                "Domain Cache '{}': 命中 {} (剩余 TTL: {}s)",
                 self.cache_id,
-                domain,
+                key,
                 record.remaining_ttl()
             );
-            return Some(record.message.clone());
+            return Some(self.aged_message(record));
         }
-        debug!("Domain Cache '{}': 未命中域名 {}", self.cache_id, domain);
+        debug!("Domain Cache '{}': 未命中 {}", self.cache_id, key);
         None
     }
     
-    /// 按复合KEY查询缓存（cache_id + match_domain + upstream + qname）
-    pub fn get_by_key(&self, cache_id: &str, match_domain: &str, upstream: &str, qname: &str) -> Option<Message> {
-        let cache = self.cache.read().unwrap();
-        
-        // 遍历缓存，找到匹配的条目
-        for (cached_qname, record) in cache.iter() {
-            if cached_qname == qname 
-                && record.cache_id == cache_id 
-                && record.matched_domain == match_domain 
-                && record.upstream == upstream {
-                
+    /// 按复合KEY查询缓存（按查询键索引，再校验 cache_id + match_domain + upstream）
+    ///
+    /// 只持有分片的读锁；命中记录写入分片的无锁访问缓冲区，在下次写入分片时回放。
+    /// 启用 serve-stale 时，过期不超过 `max_stale` 的记录作为过期应答返回
+    pub fn get_by_key(&self, cache_id: &str, match_domain: &str, upstream: &str, key: &CacheKey) -> Option<CacheHit> {
+        let shard = self.cache.shard(key);
+        let (response, needs_flush) = {
+            let cache = shard.read().unwrap();
+            let response = self.lookup(&cache, cache_id, match_domain, upstream, key)?;
+            cache.record_access(key);
+            (response, cache.needs_flush())
+        };
+        // 访问缓冲区已满时尽力回放（分片正被写入时跳过，下次写入时回放）
+        if needs_flush {
+            if let Ok(mut cache) = shard.try_write() {
+                cache.flush_accesses();
+            }
+        }
+        Some(response)
+    }
+
+    fn lookup(
+        &self,
+        cache: &BoundedMap<CacheKey, CachedDnsRecord>,
+        cache_id: &str,
+        match_domain: &str,
+        upstream: &str,
+        key: &CacheKey,
+    ) -> Option<CacheHit> {
+        match cache.peek(key) {
+            Some(record) if record.cache_id == cache_id
+                && record.matched_domain == match_domain
+                && record.upstream == upstream => {
                 if record.is_expired() {
+                    if let Some(stale) = self.serve_stale.as_ref().filter(|_| !self.is_discardable(record)) {
+                        debug!("Domain Cache '{}': KEY匹配，过期应答: {}|{}|{}|{} (已过期 {}s)",
+                            self.cache_id, cache_id, match_domain, upstream, key, record.expire_at.elapsed().as_secs());
+                        return Some(CacheHit::Stale(Self::stale_message(record, stale.stale_ttl)));
+                    }
                     debug!("Domain Cache '{}': KEY匹配但已过期: {}|{}|{}|{}", 
-                        self.cache_id, cache_id, match_domain, upstream, qname);
+                        self.cache_id, cache_id, match_domain, upstream, key);
                     return None;
                 }
                 
+                let hits = record.hits.increment();
+                if self.prefetch.as_ref().is_some_and(|prefetch| hits == prefetch.min_hits.max(1)) {
+                    self.schedule_prefetch(record);
+                }
                 debug!(
                     "Domain Cache '{}': KEY命中: {}|{}|{}|{} (剩余 TTL: {}s)",
-                    self.cache_id, cache_id, match_domain, upstream, qname, record.remaining_ttl()
+                    self.cache_id, cache_id, match_domain, upstream, key, record.remaining_ttl()
                 );
-                return Some(record.message.clone());
+                Some(CacheHit::Fresh(self.aged_message(record)))
+            }
+            _ => {
+                debug!("Domain Cache '{}': KEY未命中: {}|{}|{}|{}", 
+                    self.cache_id, cache_id, match_domain, upstream, key);
+                None
             }
         }
-        
-        debug!("Domain Cache '{}': KEY未命中: {}|{}|{}|{}", 
-            self.cache_id, cache_id, match_domain, upstream, qname);
-        None
     }
 
     /// 插入缓存
-    pub fn insert(&self, domain: String, cache_id: String, matched_domain: String, upstream: String, message: Message, ttl: u64) {
-        let mut cache = self.cache.write().unwrap();
-
-        // 检查缓存大小限制，使用 LRU 淘汰策略
-        if cache.len() >= self.max_size && !cache.contains_key(&domain) {
-            // 简单 LRU：删除最早过期的条目
-            if let Some(oldest_key) = self.find_earliest_expiry(&cache) {
-                debug!(
-                    "Domain Cache '{}': 缓存已满，淘汰域名 {}",
-                    self.cache_id, oldest_key
-                );
-                cache.remove(&oldest_key);
-            }
-        }
-
+    ///
+    /// 否定应答（NXDOMAIN / NODATA）按 negative_min_ttl / negative_max_ttl 调整；调整后 TTL 为 0 时不缓存
+    pub fn insert(&self, key: CacheKey, cache_id: String, matched_domain: String, upstream: String, message: Message, ttl: u64) {
         // 应用 min_ttl 和 max_ttl 限制
-        let adjusted_ttl = self.adjust_ttl(ttl);
+        let negative = crate::dns::is_negative(&message);
+        let adjusted_ttl = if negative { self.adjust_negative_ttl(ttl) } else { self.adjust_ttl(ttl) };
+        if adjusted_ttl == 0 {
+            debug!("Domain Cache '{}': {} TTL 为 0，不缓存", self.cache_id, key);
+            return;
+        }
 
         let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-        let expire_at = Instant::now() + Duration::from_secs(adjusted_ttl);
+        let stored_at = Instant::now();
+        let expire_at = stored_at + Duration::from_secs(adjusted_ttl);
+        debug!(
+            "Domain Cache '{}': 写入{} {} (匹配域名: {}, TTL: {}s)",
+            self.cache_id, if negative { "否定应答" } else { "" }, key, matched_domain, adjusted_ttl
+        );
         let record = CachedDnsRecord {
             cache_id,
-            matched_domain: matched_domain.clone(),
-            domain: domain.clone(),
+            matched_domain,
+            key: key.clone(),
             upstream,
             original_ttl: ttl,
+            stored_at,
             expire_at,
             timestamp,
             message,
+            hits: HitCounter::default(),
+            refresh_hold: RefreshHold::default(),
         };
 
-        cache.insert(domain.clone(), record);
-        debug!(
-            "Domain Cache '{}': 写入域名 {} (匹配域名: {}, TTL: {}s)",
-            self.cache_id, domain, matched_domain, adjusted_ttl
-        );
-    }
-
-    /// 删除缓存记录
-    pub fn remove(&self, domain: &str) {
-        let mut cache = self.cache.write().unwrap();
-        cache.remove(domain);
-    }
-
-    /// 清空所有缓存
-    pub fn clear(&self) {
-        let mut cache = self.cache.write().unwrap();
-        let count = cache.len();
-        cache.clear();
-        info!("Domain Cache '{}': 已清空 {} 条记录", self.cache_id, count);
+        // 缓存已满时按淘汰策略淘汰（W-TinyLFU 下可能拒绝新条目本身）
+        let evicted = self.cache.shard(&key).write().unwrap().insert(key, record);
+        if let Some(evicted) = evicted {
+            debug!("Domain Cache '{}': 缓存已满，淘汰 {}", self.cache_id, evicted);
+        }
     }
 
     /// 获取缓存统计信息
     pub fn stats(&self) -> CacheStats {
-        let cache = self.cache.read().unwrap();
-        let total = cache.len();
-        let expired = cache.values().filter(|r| r.is_expired()).count();
+        let (mut total, mut expired, mut evictions) = (0, 0, 0);
+        for shard in self.cache.iter() {
+            let cache = shard.read().unwrap();
+            total += cache.len();
+            expired += cache.values().filter(|r| r.is_expired()).count();
+            evictions += cache.evictions();
+        }
         CacheStats {
             total,
             valid: total - expired,
             expired,
+            evictions,
         }
     }
 
     /// 清理过期缓存（定期调用）
     pub fn cleanup_expired(&self) {
-        let mut cache = self.cache.write().unwrap();
-        let before_count = cache.len();
-        cache.retain(|_, record| !record.is_expired());
-        let after_count = cache.len();
-        let removed = before_count - after_count;
+        let mut removed = 0;
+        for shard in self.cache.iter() {
+            let mut cache = shard.write().unwrap();
+            let before_count = cache.len();
+            cache.retain(|_, record| !self.is_discardable(record));
+            removed += before_count - cache.len();
+        }
         if removed > 0 {
             info!(
                 "Domain Cache '{}': 清理了 {} 条过期记录",
@@ -294,13 +675,69 @@ impl DomainCache {
         }
     }
     
+    /// 记录的预取时间点：剩余 TTL 降到缓存时 TTL 的 `threshold` 比例时
+    fn prefetch_due(prefetch: &PrefetchConfig, record: &CachedDnsRecord) -> Instant {
+        let ttl = record.expire_at - record.stored_at;
+        record.expire_at - ttl.mul_f64(prefetch.threshold.clamp(0.0, 1.0))
+    }
+
+    /// 命中次数达到 `min_hits` 的记录加入预取队列（队列已满时忽略）
+    fn schedule_prefetch(&self, record: &CachedDnsRecord) {
+        let Some(prefetch) = &self.prefetch else {
+            return;
+        };
+        let mut queue = self.prefetch_queue.lock().unwrap();
+        if queue.len() >= self.max_size {
+            debug!("Domain Cache '{}': 预取队列已满，跳过 {}", self.cache_id, record.key);
+            return;
+        }
+        queue.push(Reverse(PrefetchEntry { due: Self::prefetch_due(prefetch, record), key: record.key.clone() }));
+    }
+
+    /// 需要预取的记录：命中次数达到 `min_hits`，且剩余 TTL 不超过缓存时 TTL 的 `threshold` 比例
+    ///
+    /// 只检查预取队列中已到预取时间的记录；记录已删除、已过期或已被重新写入（命中次数重新计数）时跳过
+    /// 返回: Vec<(key, match_domain, upstream, cache_id)>
+    pub fn prefetch_candidates(&self) -> Vec<(CacheKey, String, String, String)> {
+        let Some(prefetch) = &self.prefetch else {
+            return Vec::new();
+        };
+        let now = Instant::now();
+        let mut due = Vec::new();
+        {
+            let mut queue = self.prefetch_queue.lock().unwrap();
+            while queue.peek().is_some_and(|Reverse(entry)| entry.due <= now) {
+                due.push(queue.pop().unwrap().0.key);
+            }
+        }
+
+        let mut candidates = Vec::new();
+        for key in due {
+            let cache = self.cache.shard(&key).read().unwrap();
+            let Some(record) = cache.peek(&key) else {
+                continue;
+            };
+            if record.is_expired() || record.hits.get() < prefetch.min_hits || Self::prefetch_due(prefetch, record) > now {
+                continue;
+            }
+            candidates.push((
+                record.key.clone(),
+                record.matched_domain.clone(),
+                record.upstream.clone(),
+                record.cache_id.clone(),
+            ));
+        }
+        candidates
+    }
+
     /// 验证 domain.cache 条目是否有对应的有效 rule.cache 条目
+    ///
+    /// 旧格式缓存文件中的条目只在首次验证时使用：有效的加入预热列表，无效的计入 invalid_count
     /// 返回: (valid_records, invalid_count, warm_up_list)
     pub fn validate_against_rule_cache(
         &self,
         valid_rule_entries: &[(String, String, String)],
-    ) -> (Vec<CachedDnsRecord>, usize, Vec<(String, String, String, String)>) {
-        let cache = self.cache.read().unwrap();
+    ) -> (Vec<CachedDnsRecord>, usize, Vec<(CacheKey, String, String, String)>) {
         let mut valid_records = Vec::new();
         let mut invalid_count = 0;
         let mut warm_up_list = Vec::new();
@@ -311,21 +748,32 @@ impl DomainCache {
             valid_keys.insert((match_domain.clone(), upstream.clone()));
         }
         
-        for (domain, record) in cache.iter() {
-            let key = (record.matched_domain.clone(), record.upstream.clone());
+        let records = self.cache.iter()
+            .flat_map(|shard| shard.read().unwrap().values().cloned().collect::<Vec<_>>());
+        for record in records {
+            let rule_key = (record.matched_domain.clone(), record.upstream.clone());
             
-            if valid_keys.contains(&key) {
+            if valid_keys.contains(&rule_key) {
                 // 记录有效，但需要预热（重新查询）
                 valid_records.push(record.clone());
                 warm_up_list.push((
-                    domain.clone(),
+                    record.key.clone(),
                     record.matched_domain.clone(),
                     record.upstream.clone(),
                     record.cache_id.clone(),
                 ));
             } else {
                 invalid_count += 1;
-                debug!("Domain Cache '{}' 冷启动验证: 移除无效条目 {} (规则不存在)", self.cache_id, domain);
+                debug!("Domain Cache '{}' 冷启动验证: 移除无效条目 {} (规则不存在)", self.cache_id, record.key);
+            }
+        }
+
+        for (key, matched_domain, upstream, cache_id) in std::mem::take(&mut *self.legacy_warm_up.lock().unwrap()) {
+            if valid_keys.contains(&(matched_domain.clone(), upstream.clone())) {
+                warm_up_list.push((key, matched_domain, upstream, cache_id));
+            } else {
+                invalid_count += 1;
+                debug!("Domain Cache '{}' 冷启动验证: 移除无效的旧格式条目 {} (规则不存在)", self.cache_id, key);
             }
         }
         
@@ -334,32 +782,49 @@ impl DomainCache {
     
     /// 导出缓存到文件
     pub fn export_to_file(&self) -> Result<()> {
+        use base64::engine::general_purpose::STANDARD;
+        use base64::Engine;
+
         if let Some(ref output_path) = self.output_path {
             // 创建输出目录
             if let Some(parent) = Path::new(output_path).parent() {
                 fs::create_dir_all(parent)?;
             }
             
-            let cache = self.cache.read().unwrap();
-            let mut file = File::create(output_path)?;
-            
-            // 只导出未过期的条目，按过期时间排序
-            let mut entries: Vec<_> = cache.values()
-                .filter(|e| !e.is_expired())
+            // 只导出未过期的条目，按过期时间排序（逐个分片复制，写文件时不持有锁）
+            let mut entries: Vec<CachedDnsRecord> = self.cache.iter()
+                .flat_map(|shard| {
+                    let cache = shard.read().unwrap();
+                    cache.values().filter(|e| !e.is_expired()).cloned().collect::<Vec<_>>()
+                })
                 .collect();
             entries.sort_by_key(|e| e.timestamp);
             
+            let mut file = File::create(output_path)?;
+            writeln!(file, "{}", DOMAIN_CACHE_FILE_HEADER)?;
             for entry in &entries {
+                let message = match crate::dns::encode_dns(&entry.message) {
+                    Ok(wire) => STANDARD.encode(wire),
+                    Err(e) => {
+                        debug!("Domain Cache '{}': 编码 {} 的响应失败: {}", self.cache_id, entry.key, e);
+                        continue;
+                    }
+                };
                 // 提取 IP 信息
                 let ip_info = Self::extract_ip_info(&entry.message);
                 
-                // 格式: |cache ID|match domain|upstream|qname|ttl|IP(及其它信息)|
-                writeln!(file, "|{}|{}|{}|{}|{}|{}|", 
+                // 格式: |cache ID|match domain|upstream|qname|qtype|qclass|flags|stored|expires|message|IP(及其它信息)|
+                writeln!(file, "|{}|{}|{}|{}|{}|{}|{}|{}|{}|{}|{}|", 
                     entry.cache_id, 
                     entry.matched_domain, 
                     entry.upstream,
-                    entry.domain,
-                    entry.remaining_ttl(),
+                    entry.key.qname,
+                    entry.key.qtype,
+                    entry.key.qclass,
+                    entry.key.flags(),
+                    entry.timestamp,
+                    entry.expires_timestamp(),
+                    message,
                     ip_info)?;
             }
             
@@ -368,6 +833,41 @@ impl DomainCache {
         Ok(())
     }
     
+    /// 复制缓存的响应，各记录的 TTL 改为剩余时间（按 min_ttl / max_ttl 调整后减去已缓存的时间）
+    ///
+    /// 否定应答的记录（SOA 等）TTL 统一为按 negative_min_ttl / negative_max_ttl 调整后的缓存时间减去已缓存的时间
+    fn aged_message(&self, record: &CachedDnsRecord) -> Message {
+        let elapsed = record.stored_at.elapsed().as_secs();
+        let negative = crate::dns::is_negative(&record.message);
+        let age = |records: &mut Vec<Record>| {
+            for rr in records {
+                let remaining = if negative {
+                    (record.expire_at - record.stored_at).as_secs().saturating_sub(elapsed)
+                } else {
+                    self.adjust_ttl(rr.ttl() as u64).saturating_sub(elapsed)
+                };
+                rr.set_ttl(remaining.min(u32::MAX as u64) as u32);
+            }
+        };
+
+        let mut message = record.message.clone();
+        age(message.answers_mut());
+        age(message.name_servers_mut());
+        age(message.additionals_mut());
+        message
+    }
+
+    /// 复制过期记录的响应，各记录的 TTL 改为 `stale_ttl`
+    fn stale_message(record: &CachedDnsRecord, stale_ttl: u32) -> Message {
+        let set_ttl = |records: &mut Vec<Record>| records.iter_mut().for_each(|rr| { rr.set_ttl(stale_ttl); });
+
+        let mut message = record.message.clone();
+        set_ttl(message.answers_mut());
+        set_ttl(message.name_servers_mut());
+        set_ttl(message.additionals_mut());
+        message
+    }
+
     /// 从 DNS 消息提取 IP 信息
     fn extract_ip_info(message: &Message) -> String {
         let mut ips = Vec::new();
@@ -383,14 +883,6 @@ impl DomainCache {
         }
     }
 
-    /// 查找最早过期的条目（用于 LRU 淘汰）
-    fn find_earliest_expiry(&self, cache: &HashMap<String, CachedDnsRecord>) -> Option<String> {
-        cache
-            .iter()
-            .min_by_key(|(_, record)| record.expire_at)
-            .map(|(key, _)| key.clone())
-    }
-
     /// 调整 TTL（应用 min_ttl 和 max_ttl 限制）
     fn adjust_ttl(&self, ttl: u64) -> u64 {
         let mut adjusted = ttl;
@@ -406,6 +898,18 @@ impl DomainCache {
         }
         adjusted
     }
+
+    /// 调整否定应答的 TTL（应用 negative_min_ttl 和 negative_max_ttl 限制，后者未配置时使用 max_ttl）
+    fn adjust_negative_ttl(&self, ttl: u64) -> u64 {
+        let mut adjusted = ttl;
+        if let Some(min) = self.negative_min_ttl {
+            adjusted = adjusted.max(min);
+        }
+        if let Some(max) = self.negative_max_ttl.or(self.max_ttl) {
+            adjusted = adjusted.min(max);
+        }
+        adjusted
+    }
 }
 
 /// 缓存统计信息
@@ -417,6 +921,8 @@ pub struct CacheStats {
     pub valid: usize,
     /// 过期记录数
     pub expired: usize,
+    /// 因容量限制淘汰的记录数（累计）
+    pub evictions: u64,
 }
 
 /// Rule Cache（规则缓存）
@@ -424,8 +930,8 @@ pub struct CacheStats {
 /// 用于加速 DNS 解析，避免重复的规则匹配
 #[derive(Clone)]
 pub struct RuleCache {
-    /// 缓存数据（domain -> (upstream_name, cache_id)）
-    cache: Arc<RwLock<HashMap<String, (String, String)>>>,
+    /// 缓存数据（domain -> (upstream_name, cache_id)，按域名哈希分片）
+    cache: Arc<Shards<HashMap<String, (String, String)>>>,
     /// 缓存输出文件路径
     output_path: Option<String>,
     /// 默认上游服务器（YAML 顺序最后一个）
@@ -437,7 +943,7 @@ impl RuleCache {
     pub fn new() -> Self {
         info!("创建 Rule Cache (内存规则缓存)");
         Self {
-            cache: Arc::new(RwLock::new(HashMap::new())),
+            cache: Arc::new(Shards::new(1, HashMap::new)),
             output_path: None,
             default_upstream: String::new(),
         }
@@ -445,7 +951,7 @@ impl RuleCache {
     
     /// 从配置创建 Rule Cache
     pub fn from_config(config: &CacheConfig, default_upstream: String) -> Self {
-        let cache = Arc::new(RwLock::new(HashMap::new()));
+        let cache = Shards::new(config.shards, HashMap::new);
         
         // 如果配置了输出文件且启用了冷启动，尝试加载
         if let Some(ref output_path) = config.output {
@@ -456,16 +962,16 @@ impl RuleCache {
             }
         }
         
-        info!("创建 Rule Cache: default_upstream={}, output={:?}", default_upstream, config.output);
+        info!("创建 Rule Cache: default_upstream={}, shards={}, output={:?}", default_upstream, cache.len(), config.output);
         Self {
-            cache,
+            cache: Arc::new(cache),
             output_path: config.output.clone(),
             default_upstream,
         }
     }
     
     /// 从文件加载缓存
-    fn load_from_file(path: &str, cache: &Arc<RwLock<HashMap<String, (String, String)>>>) -> Result<()> {
+    fn load_from_file(path: &str, cache: &Shards<HashMap<String, (String, String)>>) -> Result<()> {
         if !Path::new(path).exists() {
             return Ok(());
         }
@@ -488,7 +994,7 @@ impl RuleCache {
             let domain = parts[1].to_string();
             let upstream = parts[2].to_string();
             
-            cache.write().unwrap().insert(domain, (upstream, cache_id));
+            cache.shard(&domain).write().unwrap().insert(domain, (upstream, cache_id));
             loaded += 1;
         }
         
@@ -498,7 +1004,7 @@ impl RuleCache {
 
     /// 查询缓存
     pub fn get(&self, domain: &str) -> Option<(String, String)> {
-        let cache = self.cache.read().unwrap();
+        let cache = self.cache.shard(domain).read().unwrap();
         if let Some((upstream, cache_id)) = cache.get(domain) {
             debug!("Rule Cache 命中: {} -> {} (cache_id: {})", domain, upstream, cache_id);
             return Some((upstream.clone(), cache_id.clone()));
@@ -508,71 +1014,48 @@ impl RuleCache {
     }
     
     /// 按域名深度查询匹配的 match domain（深度大者优先）
+    ///
+    /// 依次查找 qname 自身、各级父域名与根域名 "."，每级只访问对应的分片
     /// 返回: Vec<(match_domain, upstream, cache_id)>
     pub fn get_matches_by_depth(&self, qname: &str) -> Vec<(String, String, String)> {
-        let cache = self.cache.read().unwrap();
+        let qname = qname.to_ascii_lowercase();
+        let labels: Vec<&str> = qname.split('.').filter(|s| !s.is_empty()).collect();
         let mut matches = Vec::new();
-        
-        // 遍历所有 match domain，找到匹配 qname 的条目
-        for (match_domain, (upstream, cache_id)) in cache.iter() {
-            if Self::domain_matches(qname, match_domain) {
+
+        for depth in (0..=labels.len()).rev() {
+            let match_domain = if depth == 0 {
+                ".".to_string()
+            } else {
+                labels[labels.len() - depth..].join(".")
+            };
+            let cache = self.cache.shard(&match_domain).read().unwrap();
+            if let Some((upstream, cache_id)) = cache.get(&match_domain) {
                 matches.push((match_domain.clone(), upstream.clone(), cache_id.clone()));
             }
         }
         
-        // 按域名深度排序（深度大者优先）
-        matches.sort_by(|a, b| {
-            let depth_a = Self::get_domain_depth(&a.0);
-            let depth_b = Self::get_domain_depth(&b.0);
-            depth_b.cmp(&depth_a) // 降序
-        });
-        
         if !matches.is_empty() {
             debug!("Rule Cache 按深度匹配: {} -> {} 个匹配项", qname, matches.len());
         }
         
         matches
     }
-    
-    /// 计算域名深度
-    fn get_domain_depth(domain: &str) -> usize {
-        if domain == "." {
-            return 0;
-        }
-        domain.matches('.').count() + 1
-    }
-    
-    /// 判断 qname 是否匹配 match_domain
-    fn domain_matches(qname: &str, match_domain: &str) -> bool {
-        if match_domain == "." {
-            return true; // 根域名匹配所有
-        }
-        
-        // 精确匹配
-        if qname == match_domain {
-            return true;
-        }
-        
-        // 后缀匹配：qname 以 .match_domain 结尾
-        if qname.ends_with(&format!(".{}", match_domain)) {
-            return true;
-        }
-        
-        false
-    }
 
     /// 插入缓存
     pub fn insert(&self, domain: String, upstream: String, cache_id: String) {
-        let mut cache = self.cache.write().unwrap();
+        let mut cache = self.cache.shard(&domain).write().unwrap();
         cache.insert(domain.clone(), (upstream.clone(), cache_id.clone()));
         debug!("Rule Cache 写入: {} -> {} (cache_id: {})", domain, upstream, cache_id);
     }
 
     /// 清空所有缓存（reload 时调用）
     pub fn clear(&self) {
-        let mut cache = self.cache.write().unwrap();
-        let count = cache.len();
-        cache.clear();
+        let mut count = 0;
+        for shard in self.cache.iter() {
+            let mut cache = shard.write().unwrap();
+            count += cache.len();
+            cache.clear();
+        }
         info!("Rule Cache 已清空: {} 条记录", count);
     }
     
@@ -583,11 +1066,10 @@ impl RuleCache {
         rules: &IndexMap<String, Vec<String>>,
         lists: &HashMap<String, Vec<String>>,
     ) -> (Vec<(String, String, String)>, usize) {
-        let cache = self.cache.read().unwrap();
         let mut valid_entries = Vec::new();
         let mut invalid_count = 0;
         
-        for (match_domain, (upstream, cache_id)) in cache.iter() {
+        for (match_domain, upstream, cache_id) in &self.entries() {
             // 根域名 "." 禁止参与冷启动机制
             if match_domain == "." {
                 invalid_count += 1;
@@ -641,14 +1123,28 @@ impl RuleCache {
     
     /// 使用验证后的条目重新构建缓存
     pub fn rebuild_from_validated(&self, valid_entries: Vec<(String, String, String)>) {
-        let mut cache = self.cache.write().unwrap();
-        cache.clear();
+        for shard in self.cache.iter() {
+            shard.write().unwrap().clear();
+        }
         
+        let count = valid_entries.len();
         for (match_domain, upstream, cache_id) in valid_entries {
-            cache.insert(match_domain, (upstream, cache_id));
+            self.cache.shard(&match_domain).write().unwrap().insert(match_domain, (upstream, cache_id));
         }
         
-        info!("Rule Cache 冷启动: 重建完成，共 {} 条有效记录", cache.len());
+        info!("Rule Cache 冷启动: 重建完成，共 {} 条有效记录", count);
+    }
+
+    /// 复制所有条目：(match_domain, upstream, cache_id)
+    fn entries(&self) -> Vec<(String, String, String)> {
+        self.cache.iter()
+            .flat_map(|shard| {
+                let cache = shard.read().unwrap();
+                cache.iter()
+                    .map(|(domain, (upstream, cache_id))| (domain.clone(), upstream.clone(), cache_id.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
     }
     
     /// 导出缓存到文件
@@ -659,28 +1155,25 @@ impl RuleCache {
                 fs::create_dir_all(parent)?;
             }
             
-            let cache = self.cache.read().unwrap();
-            let mut file = File::create(output_path)?;
-            
             // 按域名排序输出
-            let mut entries: Vec<_> = cache.iter().collect();
-            entries.sort_by_key(|(domain, _)| *domain);
+            let mut entries = self.entries();
+            entries.sort();
             
-            for (domain, (upstream, cache_id)) in entries {
+            let mut file = File::create(output_path)?;
+            for (domain, upstream, cache_id) in &entries {
                 // 格式: |cache ID|match domain|upstream|
                 writeln!(file, "|{}|{}|{}|", cache_id, domain, upstream)?;
             }
             
-            info!("Rule Cache: 已导出 {} 条缓存到 {}", cache.len(), output_path);
+            info!("Rule Cache: 已导出 {} 条缓存到 {}", entries.len(), output_path);
         }
         Ok(())
     }
 
     /// 获取缓存统计信息
     pub fn stats(&self) -> RuleCacheStats {
-        let cache = self.cache.read().unwrap();
         RuleCacheStats {
-            total: cache.len(),
+            total: self.cache.iter().map(|shard| shard.read().unwrap().len()).sum(),
         }
     }
 }
@@ -757,6 +1250,60 @@ impl CacheManager {
     pub fn get_domain_cache(&self, name: &str) -> Option<Arc<DomainCache>> {
         self.domain_caches.get(name).cloned()
     }
+
+    /// 启动热点记录预取：定期检查启用了预取的域名缓存，将即将过期的热点记录
+    /// 通过记录中保存的上游重新查询并写回缓存（无需重新匹配规则）
+    ///
+    /// `query(upstream, request)` 向指定名称的上游列表发送查询
+    pub fn spawn_prefetch<F, Fut>(&self, query: F)
+    where
+        F: Fn(String, Message) -> Fut + Clone + Send + Sync + 'static,
+        Fut: Future<Output = Result<Message>> + Send + 'static,
+    {
+        for (name, cache) in &self.domain_caches {
+            let Some(prefetch) = &cache.prefetch else {
+                continue;
+            };
+            info!("域名缓存 '{}' 已启用预取: min_hits={}, threshold={}", name, prefetch.min_hits, prefetch.threshold);
+
+            let cache = Arc::clone(cache);
+            let query = query.clone();
+            tokio::spawn(async move {
+                // 进行中的预取，避免同一记录重复预取
+                let inflight: Arc<Mutex<HashSet<CacheKey>>> = Arc::new(Mutex::new(HashSet::new()));
+                loop {
+                    tokio::time::sleep(PREFETCH_SCAN_INTERVAL).await;
+
+                    for (key, matched_domain, upstream, cache_id) in cache.prefetch_candidates() {
+                        if !inflight.lock().unwrap().insert(key.clone()) {
+                            continue;
+                        }
+                        let request = match key.to_request() {
+                            Ok(request) => request,
+                            Err(e) => {
+                                warn!("预取: 域名格式错误 '{}': {}", key.qname, e);
+                                inflight.lock().unwrap().remove(&key);
+                                continue;
+                            }
+                        };
+
+                        let (cache, query, inflight) = (Arc::clone(&cache), query.clone(), Arc::clone(&inflight));
+                        tokio::spawn(async move {
+                            debug!("预取: {} -> {}", key, upstream);
+                            match query(upstream.clone(), request).await {
+                                Ok(response) => match crate::dns::response_ttl(&response) {
+                                    Some(ttl) => cache.insert(key.clone(), cache_id, matched_domain, upstream, response, ttl),
+                                    None => warn!("预取 {} 失败: 上游返回 {}", key, response.response_code()),
+                                },
+                                Err(e) => warn!("预取 {} 失败: {}", key, e),
+                            }
+                            inflight.lock().unwrap().remove(&key);
+                        });
+                    }
+                }
+            });
+        }
+    }
     
     /// 导出所有缓存到文件
     pub fn export_all(&self) -> Result<()> {
@@ -786,7 +1333,7 @@ impl CacheManager {
     pub async fn cold_start(
         &self,
         config: &Config,
-    ) -> Result<Vec<(String, String, String, String)>> {
+    ) -> Result<Vec<(CacheKey, String, String, String)>> {
         info!("开始缓存冷启动流程...");
         let mut all_warm_up_list = Vec::new();
         
@@ -909,8 +1456,8 @@ impl CacheManager {
         
         for (name, cache) in &self.domain_caches {
             let cache_stats = cache.stats();
-            stats.push_str(&format!("域名缓存 '{}': {}/{} (有效: {})\n", 
-                name, cache_stats.total, cache_stats.total, cache_stats.valid));
+            stats.push_str(&format!("域名缓存 '{}': {}/{} (有效: {}, 淘汰: {})\n",
+                name, cache_stats.total, cache.max_size, cache_stats.valid, cache_stats.evictions));
         }
         
         stats
@@ -920,10 +1467,30 @@ impl CacheManager {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use hickory_proto::op::{Message, Query};
-    use hickory_proto::rr::{Name, RecordType};
+    use hickory_proto::op::{Message, Query, ResponseCode};
+    use hickory_proto::rr::{Name, RData, RecordType};
     use std::str::FromStr;
 
+    /// 构造测试响应：查询部分为 `name`/`qtype`，应答部分为给定 (TTL, 数据) 的记录
+    fn response(name: &str, qtype: RecordType, answers: Vec<(u32, RData)>) -> Message {
+        let name = Name::from_str(name).unwrap();
+        let mut msg = Message::new();
+        msg.add_query(Query::query(name.clone(), qtype));
+        for (ttl, rdata) in answers {
+            msg.add_answer(Record::from_rdata(name.clone(), ttl, rdata));
+        }
+        msg
+    }
+
+    /// 模拟记录已缓存了 `by`：写入时间与过期时间同时提前
+    fn age_record(cache: &DomainCache, key: &CacheKey, by: Duration) {
+        let mut shard = cache.cache.shard(key).write().unwrap();
+        let mut record = shard.peek(key).unwrap().clone();
+        record.stored_at -= by;
+        record.expire_at -= by;
+        shard.insert(key.clone(), record);
+    }
+
     #[test]
     fn test_domain_cache_basic() {
         let cache = DomainCache::new("test".to_string(), 10, None, None);
@@ -935,7 +1502,7 @@ mod tests {
 
         // 测试插入
         cache.insert(
-            "example.com".to_string(),
+            CacheKey::new("example.com.", RecordType::A),
             "test_cache".to_string(),
             "example.com".to_string(),
             "test_upstream".to_string(),
@@ -944,8 +1511,10 @@ mod tests {
         );
 
         // 测试查询
-        assert!(cache.get("example.com").is_some());
-        assert!(cache.get("not-exist.com").is_none());
+        assert!(cache.get(&CacheKey::new("example.com.", RecordType::A)).is_some());
+        assert!(cache.get(&CacheKey::new("not-exist.com.", RecordType::A)).is_none());
+        // 同一域名的其他查询类型不命中
+        assert!(cache.get(&CacheKey::new("example.com.", RecordType::AAAA)).is_none());
 
         // 测试统计
         let stats = cache.stats();
@@ -966,25 +1535,239 @@ mod tests {
     #[test]
     fn test_domain_cache_expiry() {
         let cache = DomainCache::new("test".to_string(), 10, None, None);
-
-        let mut msg = Message::new();
-        let name = Name::from_str("example.com.").unwrap();
-        msg.add_query(Query::query(name, RecordType::A));
-
-        // 插入短 TTL 记录
+        let key = CacheKey::new("example.com.", RecordType::A);
         cache.insert(
-            "example.com".to_string(),
+            key.clone(),
             "test_cache".to_string(),
             "example.com".to_string(),
             "test_upstream".to_string(),
-            msg,
-            0, // 立即过期
+            response("example.com.", RecordType::A, vec![(300, RData::A("192.0.2.1".parse().unwrap()))]),
+            300,
         );
+        assert!(cache.get(&key).is_some());
+
+        // 模拟 TTL 已耗尽：应该已过期，定期清理时删除
+        age_record(&cache, &key, Duration::from_secs(300));
+        assert!(cache.get(&key).is_none());
+        assert_eq!(cache.stats().expired, 1);
+        cache.cleanup_expired();
+        assert_eq!(cache.stats().total, 0);
+    }
+
+    #[test]
+    fn test_domain_cache_file_format() {
+        let dir = std::env::temp_dir().join(format!("creskydns-cache-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // 旧格式不含完整响应，不写入缓存，条目只用于预热（无版本标记的文件按 A/IN 记录迁移）
+        let legacy_path = dir.join("legacy.cache.txt");
+        fs::write(&legacy_path, "|main|example.com|ali|www.example.com.|300|1.2.3.4|\n").unwrap();
+        let v2_path = dir.join("v2.cache.txt");
+        fs::write(&v2_path, "# creskyDNS domain cache v2\n|main|example.com|ali|www.example.com.|AAAA|IN|DO|300|2001:db8::1|\n").unwrap();
+        let mut v2_key = CacheKey::new("www.example.com.", RecordType::AAAA);
+        v2_key.dnssec_ok = true;
+        for (path, key) in [(&legacy_path, CacheKey::new("www.example.com.", RecordType::A)), (&v2_path, v2_key)] {
+            let loaded = DomainCache::new_shards(10, 1, EvictionPolicy::Lru);
+            let warm_up = DomainCache::load_from_file(path.to_str().unwrap(), &loaded, "main").unwrap();
+            assert_eq!(loaded.shard(&()).read().unwrap().len(), 0);
+            assert_eq!(warm_up, vec![(key, "example.com".to_string(), "ali".to_string(), "main".to_string())]);
+        }
 
-        // 稍等一下
-        std::thread::sleep(Duration::from_millis(10));
+        // 导出后重新加载，类型、类别、DO/CD 位、完整响应与剩余 TTL 保持不变
+        let mut cache = DomainCache::new("main".to_string(), 10, None, None);
+        let path = dir.join("main.cache.txt");
+        cache.output_path = Some(path.to_str().unwrap().to_string());
+        let mut key = CacheKey::new("www.example.com.", RecordType::AAAA);
+        key.dnssec_ok = true;
+        key.checking_disabled = true;
+        let name = Name::from_str("www.example.com.").unwrap();
+        let mut msg = Message::new();
+        msg.add_query(Query::query(name.clone(), RecordType::AAAA));
+        msg.add_answer(Record::from_rdata(name.clone(), 300, RData::AAAA("2001:db8::1".parse().unwrap())));
+        cache.insert(key.clone(), "main".to_string(), "example.com".to_string(), "ali".to_string(), msg.clone(), 300);
+        let mx_key = CacheKey::new("www.example.com.", RecordType::MX);
+        cache.insert(mx_key.clone(), "main".to_string(), "example.com".to_string(), "ali".to_string(), Message::new(), 300);
+        // 导出时已过期的条目不写入文件
+        {
+            let mut shard = cache.cache.shard(&mx_key).write().unwrap();
+            let mut record = shard.peek(&mx_key).unwrap().clone();
+            record.expire_at = record.stored_at;
+            shard.insert(mx_key.clone(), record);
+        }
+        cache.export_to_file().unwrap();
+        // 加载时已过期的条目被丢弃
+        let content = fs::read_to_string(&path).unwrap();
+        fs::write(&path, format!("{}|main|example.com|ali|old.example.com.|A|IN|-|100|200|AAAA|-|\n", content)).unwrap();
+
+        let reloaded = DomainCache::new_shards(10, 1, EvictionPolicy::Lru);
+        DomainCache::load_from_file(path.to_str().unwrap(), &reloaded, "main").unwrap();
+        let reloaded = reloaded.shard(&()).read().unwrap();
+        assert_eq!(reloaded.len(), 1);
+        let record = reloaded.peek(&key).unwrap();
+        assert_eq!((record.matched_domain.as_str(), record.upstream.as_str()), ("example.com", "ali"));
+        assert_eq!(record.message.answers(), msg.answers());
+        assert!((299..=300).contains(&record.remaining_ttl()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_domain_cache_legacy_warm_up() {
+        let cache = DomainCache::new("main".to_string(), 10, None, None);
+        let key = CacheKey::new("www.example.com.", RecordType::A);
+        let stale_rule = CacheKey::new("www.example.org.", RecordType::A);
+        *cache.legacy_warm_up.lock().unwrap() = vec![
+            (key.clone(), "example.com".to_string(), "ali".to_string(), "main".to_string()),
+            (stale_rule, "example.org".to_string(), "ali".to_string(), "main".to_string()),
+        ];
+        let valid_rules = vec![("example.com".to_string(), "ali".to_string(), "main".to_string())];
+
+        // 旧格式条目不参与应答，只在首次验证时按 rule.cache 加入预热列表
+        assert!(cache.get(&key).is_none());
+        let (valid_records, invalid_count, warm_up_list) = cache.validate_against_rule_cache(&valid_rules);
+        assert!(valid_records.is_empty());
+        assert_eq!(invalid_count, 1);
+        assert_eq!(warm_up_list, vec![(key, "example.com".to_string(), "ali".to_string(), "main".to_string())]);
+        assert!(cache.validate_against_rule_cache(&valid_rules).2.is_empty());
+    }
+
+    #[test]
+    fn test_domain_cache_decrements_ttl() {
+        let cache = DomainCache::new("test".to_string(), 10, Some(60), None);
+        let key = CacheKey::new("example.com.", RecordType::A);
+        let msg = response("example.com.", RecordType::A, vec![
+            (300, RData::A("192.0.2.1".parse().unwrap())),
+            (10, RData::A("192.0.2.2".parse().unwrap())),
+        ]);
+        cache.insert(key.clone(), "test".to_string(), ".".to_string(), "up".to_string(), msg, 10);
+
+        // 模拟已缓存 20 秒
+        age_record(&cache, &key, Duration::from_secs(20));
+        let Some(CacheHit::Fresh(aged)) = cache.get_by_key("test", ".", "up", &key) else {
+            panic!("应命中未过期记录");
+        };
+        let ttls: Vec<u32> = aged.answers().iter().map(Record::ttl).collect();
+        // 低于 min_ttl 的记录按 min_ttl 计算剩余时间
+        assert_eq!(ttls, vec![280, 40]);
+    }
+
+    #[test]
+    fn test_domain_cache_serve_stale() {
+        let mut cache = DomainCache::new("test".to_string(), 10, None, None);
+        cache.serve_stale = Some(ServeStaleConfig { enabled: true, max_stale: 60, stale_ttl: 30, client_timeout: 0, failure_recheck: 30 });
+        let key = CacheKey::new("example.com.", RecordType::A);
+        let name = Name::from_str("example.com.").unwrap();
+        let mut msg = Message::new();
+        msg.add_answer(Record::from_rdata(name, 300, RData::A("192.0.2.1".parse().unwrap())));
+        cache.insert(key.clone(), "test".to_string(), ".".to_string(), "up".to_string(), msg, 300);
+        let expire = |seconds: u64| {
+            let mut shard = cache.cache.shard(&key).write().unwrap();
+            let mut record = shard.peek(&key).unwrap().clone();
+            record.expire_at -= Duration::from_secs(seconds);
+            shard.insert(key.clone(), record);
+        };
+        expire(300);
+
+        // 过期不超过 max_stale：返回过期应答，TTL 为 stale_ttl，定期清理时保留
+        let Some(CacheHit::Stale(stale)) = cache.get_by_key("test", ".", "up", &key) else {
+            panic!("应返回过期应答");
+        };
+        assert_eq!(stale.answers()[0].ttl(), 30);
+        cache.cleanup_expired();
+        assert_eq!(cache.stats().total, 1);
+
+        // 刷新失败后在暂停期内不再刷新
+        assert!(!cache.stale_refresh_held(&key));
+        cache.hold_stale_refresh(&key, Duration::from_secs(30));
+        assert!(cache.stale_refresh_held(&key));
+        cache.hold_stale_refresh(&key, Duration::ZERO);
+        assert!(!cache.stale_refresh_held(&key));
+
+        // 超过 max_stale：不再应答，定期清理时删除
+        expire(61);
+        assert!(cache.get_by_key("test", ".", "up", &key).is_none());
+        cache.cleanup_expired();
+        assert_eq!(cache.stats().total, 0);
+    }
+
+    #[test]
+    fn test_domain_cache_prefetch_candidates() {
+        let mut cache = DomainCache::new("test".to_string(), 10, None, None);
+        cache.prefetch = Some(PrefetchConfig { enabled: true, min_hits: 2, threshold: 0.1 });
+        let hot = CacheKey::new("hot.example.com.", RecordType::A);
+        let cold = CacheKey::new("cold.example.com.", RecordType::A);
+        let fresh = CacheKey::new("fresh.example.com.", RecordType::A);
+        for key in [&hot, &cold, &fresh] {
+            cache.insert(key.clone(), "test".to_string(), ".".to_string(), "up".to_string(), Message::new(), 100);
+        }
+        // 剩余 TTL 降到 10% 以内
+        for key in [&hot, &cold] {
+            age_record(&cache, key, Duration::from_secs(95));
+        }
+
+        // 命中次数达到 min_hits 时加入预取队列；未到预取时间的记录留在队列中
+        assert!(cache.get_by_key("test", ".", "up", &hot).is_some());
+        assert!(cache.prefetch_candidates().is_empty());
+        for key in [&hot, &cold, &fresh, &fresh] {
+            assert!(cache.get_by_key("test", ".", "up", key).is_some());
+        }
+        let candidates = cache.prefetch_candidates();
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0], (hot, ".".to_string(), "up".to_string(), "test".to_string()));
+        assert!(cache.prefetch_candidates().is_empty());
+        assert_eq!(cache.prefetch_queue.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_domain_cache_negative_ttl() {
+        let mut cache = DomainCache::new("test".to_string(), 10, Some(600), Some(3600));
+        let key = CacheKey::new("missing.example.com.", RecordType::A);
+        let mut nxdomain = Message::new();
+        nxdomain.set_response_code(ResponseCode::NXDomain);
+        nxdomain.add_name_server(crate::dns::synthetic_soa(Name::from_str("example.com.").unwrap(), 900));
+
+        // 否定应答不受 min_ttl 影响，未配置 negative_max_ttl 时使用 max_ttl
+        assert_eq!(cache.adjust_negative_ttl(30), 30);
+        assert_eq!(cache.adjust_negative_ttl(7200), 3600);
+        cache.negative_min_ttl = Some(60);
+        cache.negative_max_ttl = Some(120);
+        assert_eq!(cache.adjust_negative_ttl(30), 60);
+
+        cache.insert(key.clone(), "test".to_string(), ".".to_string(), "up".to_string(), nxdomain, 900);
+        let Some(CacheHit::Fresh(cached)) = cache.get_by_key("test", ".", "up", &key) else {
+            panic!("应命中否定应答");
+        };
+        assert_eq!(cached.response_code(), ResponseCode::NXDomain);
+        assert_eq!(cached.name_servers()[0].ttl(), 120);
+    }
+
+    #[test]
+    fn test_shard_count() {
+        // 每个分片至少 MIN_SHARD_SIZE 条，总容量不小于配置值
+        assert_eq!(DomainCache::new_shards(10, 16, EvictionPolicy::Lru).len(), 1);
+        assert_eq!(DomainCache::new_shards(1000, 16, EvictionPolicy::Lru).len(), 3);
+        assert_eq!(DomainCache::new_shards(100000, 16, EvictionPolicy::Lru).len(), 16);
+        assert_eq!(DomainCache::new_shards(100000, 0, EvictionPolicy::Lru).len(), 1);
+    }
+
+    #[test]
+    fn test_rule_cache_matches_by_depth() {
+        let cache = RuleCache {
+            cache: Arc::new(Shards::new(8, HashMap::new)),
+            output_path: None,
+            default_upstream: String::new(),
+        };
+        cache.insert(".".to_string(), "default".to_string(), "default".to_string());
+        cache.insert("example.com".to_string(), "ali".to_string(), "ali".to_string());
+        cache.insert("www.example.com".to_string(), "google".to_string(), "google".to_string());
+        cache.insert("example.org".to_string(), "ali".to_string(), "ali".to_string());
 
-        // 应该已过期
-        assert!(cache.get("example.com").is_none());
+        let matches: Vec<String> = cache.get_matches_by_depth("a.WWW.Example.com.")
+            .into_iter()
+            .map(|(domain, upstream, _)| format!("{}@{}", domain, upstream))
+            .collect();
+        assert_eq!(matches, vec!["www.example.com@google", "example.com@ali", ".@default"]);
+        assert_eq!(cache.get_matches_by_depth("example.net.").len(), 1);
+        assert_eq!(cache.stats().total, 4);
     }
 }
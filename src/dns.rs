@@ -1,6 +1,12 @@
 use anyhow::Result;
-use hickory_proto::op::Message;
-use hickory_proto::serialize::binary::BinEncodable;
+use hickory_proto::op::{Edns, Header, Message, MessageType, ResponseCode};
+use hickory_proto::rr::rdata::opt::EdnsOption;
+use hickory_proto::rr::rdata::SOA;
+use hickory_proto::rr::{Name, RData, Record};
+use hickory_proto::serialize::binary::{BinDecodable, BinDecoder, BinEncodable};
+
+/// EDE 选项码（RFC 8914）
+const EDE_OPTION_CODE: u16 = 15;
 
 pub fn encode_dns(msg: &Message) -> Result<Vec<u8>> {
     Ok(msg.to_bytes()?)
@@ -12,4 +18,284 @@ pub fn parse_dns(buf: &[u8]) -> Result<Message> {
 
 pub fn get_qname(msg: &Message) -> Option<String> {
     msg.queries().first().map(|q| q.name().to_utf8())
-}
\ No newline at end of file
+}
+
+/// 是否为否定应答（RFC 2308）：NXDOMAIN，或没有答案记录的 NOERROR（NODATA）
+pub fn is_negative(response: &Message) -> bool {
+    match response.response_code() {
+        ResponseCode::NXDomain => true,
+        ResponseCode::NoError => response.answers().is_empty(),
+        _ => false,
+    }
+}
+
+/// 响应的缓存时间（秒），`None` 表示不可缓存
+///
+/// - 否定应答：权威部分 SOA 记录的 TTL 与其 MINIMUM 字段中的较小值（RFC 2308 第 5 节），无 SOA 时不缓存
+/// - 其他 NOERROR 响应：各记录中最小的 TTL
+/// - 其余 RCODE（SERVFAIL、REFUSED 等）不缓存
+pub fn response_ttl(response: &Message) -> Option<u64> {
+    if is_negative(response) {
+        return response.name_servers().iter().find_map(|record| match record.data() {
+            Some(RData::SOA(soa)) => Some(record.ttl().min(soa.minimum()) as u64),
+            _ => None,
+        });
+    }
+    if response.response_code() != ResponseCode::NoError {
+        return None;
+    }
+
+    response.answers().iter()
+        .chain(response.name_servers().iter())
+        .chain(response.additionals().iter())
+        .map(|record| record.ttl() as u64)
+        .min()
+}
+
+/// 合成否定应答使用的 SOA 记录（记录 TTL 与 MINIMUM 均为 `ttl`）
+pub fn synthetic_soa(zone: Name, ttl: u32) -> Record {
+    let mname = Name::from_ascii("ns.creskydns.invalid.").expect("固定域名");
+    let rname = Name::from_ascii("hostmaster.creskydns.invalid.").expect("固定域名");
+    let soa = SOA::new(mname, rname, 1, 1800, 900, 604800, ttl);
+    Record::from_rdata(zone, ttl, RData::SOA(soa))
+}
+
+/// RFC 8914 扩展 DNS 错误（EDE）信息码
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtendedError {
+    /// 0: 其他错误
+    Other = 0,
+    /// 3: 过期应答（RFC 8767 serve-stale）
+    StaleAnswer = 3,
+    /// 18: 策略禁止
+    Prohibited = 18,
+    /// 21: 不支持的操作
+    NotSupported = 21,
+    /// 22: 无法连接到上游
+    NoReachableAuthority = 22,
+}
+
+impl ExtendedError {
+    /// EDE 附带的说明文本（EXTRA-TEXT）
+    fn text(self) -> &'static str {
+        match self {
+            Self::Other => "malformed query",
+            Self::StaleAnswer => "serving stale data",
+            Self::Prohibited => "query refused by policy",
+            Self::NotSupported => "opcode not supported",
+            Self::NoReachableAuthority => "upstream query failed",
+        }
+    }
+}
+
+/// 策略拒绝查询的错误（如未匹配任何规则且无可用上游），监听器据此返回 REFUSED
+#[derive(Debug)]
+pub struct QueryRefused(pub String);
+
+impl std::fmt::Display for QueryRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for QueryRefused {}
+
+/// 合成指定 RCODE 的响应：回显请求 ID、操作码与问题部分
+pub fn rcode_response(request: &Message, rcode: ResponseCode) -> Message {
+    let mut header = Header::response_from_request(request.header());
+    header.set_authoritative(false);
+    header.set_recursion_available(true);
+    header.set_response_code(rcode);
+
+    let mut response = Message::new();
+    response.set_header(header);
+    response.add_queries(request.queries().to_vec());
+    response
+}
+
+/// 合成错误响应；客户端使用 EDNS0 且给出 `ede` 时附带 RFC 8914 扩展错误码
+pub fn error_response(request: &Message, rcode: ResponseCode, ede: Option<ExtendedError>) -> Message {
+    let mut response = rcode_response(request, rcode);
+
+    if let Some(request_edns) = request.extensions() {
+        let mut edns = Edns::new();
+        edns.set_max_payload(request_edns.max_payload().max(512));
+        edns.set_dnssec_ok(request_edns.dnssec_ok());
+        response.set_edns(edns);
+        if let Some(ede) = ede {
+            set_extended_error(&mut response, ede);
+        }
+    }
+
+    response
+}
+
+/// 在响应的 OPT 中附带 RFC 8914 扩展错误码（响应无 OPT 时不附带）
+pub fn set_extended_error(response: &mut Message, ede: ExtendedError) {
+    if let Some(edns) = response.extensions_mut() {
+        let mut data = (ede as u16).to_be_bytes().to_vec();
+        data.extend_from_slice(ede.text().as_bytes());
+        edns.options_mut().insert(EdnsOption::Unknown(EDE_OPTION_CODE, data));
+    }
+}
+
+/// 将缓存的（或其他请求共享的）响应改写为当前请求的应答
+///
+/// 回显请求 ID、问题部分（保留查询名大小写）与 RD 位，设置 RA 位；
+/// 客户端未使用 EDNS0 时移除 OPT，否则按请求回显 DO 位与缓冲区大小（上游的 EDNS 选项不转发）
+pub fn response_for_request(request: &Message, mut response: Message) -> Message {
+    response.set_id(request.id());
+    response.set_recursion_desired(request.recursion_desired());
+    response.set_recursion_available(true);
+    response.take_queries();
+    response.add_queries(request.queries().to_vec());
+
+    *response.extensions_mut() = request.extensions().as_ref().map(|request_edns| {
+        let mut edns = Edns::new();
+        edns.set_max_payload(request_edns.max_payload().max(512));
+        edns.set_dnssec_ok(request_edns.dnssec_ok());
+        edns
+    });
+    response
+}
+
+/// 为无法解析的查询合成 FORMERR 响应
+///
+/// 仅在能读出消息头且为查询时回复（回显 ID 与操作码），避免对响应报文作答造成循环
+pub fn formerr_response(data: &[u8]) -> Option<Message> {
+    let header = Header::read(&mut BinDecoder::new(data)).ok()?;
+    if header.message_type() != MessageType::Query {
+        return None;
+    }
+
+    let mut response_header = Header::response_from_request(&header);
+    response_header.set_recursion_available(true);
+    response_header.set_response_code(ResponseCode::FormErr);
+
+    let mut response = Message::new();
+    response.set_header(response_header);
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::Query;
+    use hickory_proto::rr::{Name, RData, Record, RecordType};
+    use hickory_proto::rr::rdata::opt::EdnsCode;
+    use std::str::FromStr;
+
+    fn request(edns: bool) -> Message {
+        let mut request = Message::new();
+        request.set_id(0xbeef);
+        request.set_recursion_desired(true);
+        request.add_query(Query::query(Name::from_str("example.com.").unwrap(), RecordType::A));
+        if edns {
+            request.set_edns(Edns::new());
+        }
+        request
+    }
+
+    #[test]
+    fn test_error_response_echoes_request() {
+        let response = error_response(&request(false), ResponseCode::ServFail, Some(ExtendedError::NoReachableAuthority));
+        assert_eq!(response.id(), 0xbeef);
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+        assert!(response.recursion_desired());
+        assert_eq!(response.queries(), request(false).queries());
+        // 客户端未使用 EDNS0 时不附带 OPT
+        assert!(response.extensions().is_none());
+    }
+
+    #[test]
+    fn test_error_response_extended_error() {
+        let response = error_response(&request(true), ResponseCode::Refused, Some(ExtendedError::Prohibited));
+        let parsed = Message::from_vec(&response.to_vec().unwrap()).unwrap();
+        assert_eq!(parsed.response_code(), ResponseCode::Refused);
+
+        let option = parsed.extensions().as_ref().unwrap().option(EdnsCode::from(EDE_OPTION_CODE));
+        let Some(EdnsOption::Unknown(_, data)) = option else {
+            panic!("缺少 EDE 选项: {:?}", option);
+        };
+        assert_eq!(u16::from_be_bytes([data[0], data[1]]), 18);
+        assert_eq!(&data[2..], b"query refused by policy");
+    }
+
+    #[test]
+    fn test_response_ttl() {
+        let name = Name::from_str("example.com.").unwrap();
+        let mut positive = Message::new();
+        positive.add_answer(Record::from_rdata(name.clone(), 3600, RData::A("192.0.2.1".parse().unwrap())));
+        positive.add_answer(Record::from_rdata(name.clone(), 1200, RData::A("192.0.2.2".parse().unwrap())));
+        assert_eq!(response_ttl(&positive), Some(1200));
+
+        // 否定应答取 SOA 记录 TTL 与 MINIMUM 中的较小值，无 SOA 时不缓存
+        let mut nxdomain = Message::new();
+        nxdomain.set_response_code(ResponseCode::NXDomain);
+        assert!(is_negative(&nxdomain));
+        assert_eq!(response_ttl(&nxdomain), None);
+        let mut soa = synthetic_soa(name.clone(), 900);
+        soa.set_ttl(3600);
+        nxdomain.add_name_server(soa);
+        assert_eq!(response_ttl(&nxdomain), Some(900));
+
+        let mut nodata = Message::new();
+        nodata.add_name_server(synthetic_soa(name, 60));
+        assert!(is_negative(&nodata));
+        assert_eq!(response_ttl(&nodata), Some(60));
+
+        let mut servfail = Message::new();
+        servfail.set_response_code(ResponseCode::ServFail);
+        assert!(!is_negative(&servfail));
+        assert_eq!(response_ttl(&servfail), None);
+    }
+
+    #[test]
+    fn test_formerr_response() {
+        let mut data = request(false).to_vec().unwrap();
+        data.truncate(14);
+        let response = formerr_response(&data).unwrap();
+        assert_eq!(response.id(), 0xbeef);
+        assert_eq!(response.response_code(), ResponseCode::FormErr);
+
+        // 响应报文与过短的数据不回复
+        let mut reply = request(false);
+        reply.set_message_type(MessageType::Response);
+        assert!(formerr_response(&reply.to_vec().unwrap()).is_none());
+        assert!(formerr_response(&[0x12]).is_none());
+    }
+
+    #[test]
+    fn test_response_for_request() {
+        let name = Name::from_str("example.com.").unwrap();
+        let mut cached = Message::new();
+        cached.set_id(0x1111);
+        cached.set_message_type(MessageType::Response);
+        cached.add_query(Query::query(name.clone(), RecordType::A));
+        cached.add_answer(Record::from_rdata(name, 60, RData::A("192.0.2.1".parse().unwrap())));
+        let mut upstream_edns = Edns::new();
+        upstream_edns.set_max_payload(1232);
+        cached.set_edns(upstream_edns);
+
+        let mut request = request(false);
+        request.take_queries();
+        request.add_query(Query::query(Name::from_ascii("ExAmple.COM.").unwrap(), RecordType::A));
+        let response = response_for_request(&request, cached.clone());
+        assert_eq!(response.id(), 0xbeef);
+        assert!(response.recursion_desired() && response.recursion_available());
+        assert_eq!(response.queries()[0].name().to_string(), "ExAmple.COM.");
+        assert_eq!(response.answers(), cached.answers());
+        // 客户端未使用 EDNS0 时移除 OPT
+        assert!(response.extensions().is_none());
+
+        let mut request = request.clone();
+        let mut edns = Edns::new();
+        edns.set_max_payload(4096);
+        edns.set_dnssec_ok(true);
+        request.set_edns(edns);
+        let response = response_for_request(&request, cached);
+        let edns = response.extensions().as_ref().unwrap();
+        assert_eq!((edns.max_payload(), edns.dnssec_ok()), (4096, true));
+    }
+}
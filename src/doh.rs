@@ -0,0 +1,220 @@
+use anyhow::Result;
+use hickory_proto::op::Message;
+use hyper::client::connect::dns::Name;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, StatusCode, Uri};
+use hyper_rustls::HttpsConnector;
+use rustls::ClientConfig;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tracing::debug;
+
+use crate::bootstrap::ResolveHost;
+use crate::config::DohMethod;
+use crate::proxy::Proxy;
+
+/// DoH 消息的 MIME 类型
+const DNS_MESSAGE_MIME: &str = "application/dns-message";
+
+/// DoH 响应体最大长度（DNS 消息上限）
+const MAX_DNS_MESSAGE_LEN: usize = 65535;
+
+/// 空闲连接保留时间
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// DoH 客户端（每个上游一个，长期复用）
+///
+/// 通过 ALPN 优先协商 HTTP/2，同一连接上多路复用查询；不支持 HTTP/2 的服务器使用
+/// HTTP/1.1 keep-alive 连接池。每次新建连接时经 bootstrap 解析服务器 IP（结果按 TTL 缓存），
+/// TLS SNI 与 Host 仍使用 URL 中的原始域名；配置了代理时经代理建立 TCP 连接
+pub struct DohClient {
+    client: Client<HttpsConnector<DohConnector>, Body>,
+    url: Uri,
+    method: DohMethod,
+}
+
+impl DohClient {
+    /// 创建 DoH 客户端
+    ///
+    /// `resolve` 在每次新建连接时解析服务器 IP，None 表示由代理解析；
+    /// `server_name` 覆盖 TLS SNI 及证书校验使用的名称
+    pub fn new(
+        url: &str,
+        resolve: Option<ResolveHost>,
+        proxy: Option<Proxy>,
+        server_name: Option<&str>,
+        method: DohMethod,
+        tls_config: ClientConfig,
+    ) -> Result<Self> {
+        let url: Uri = url.parse()
+            .map_err(|e| anyhow::anyhow!("无效的 DoH URL '{}': {}", url, e))?;
+        if url.scheme_str() != Some("https") || url.host().is_none() {
+            anyhow::bail!("无效的 DoH URL: {}", url);
+        }
+
+        let mut http = HttpConnector::new_with_resolver(UpstreamResolver { resolve: resolve.clone() });
+        http.enforce_http(false);
+        http.set_nodelay(true);
+        let connector = DohConnector { http, proxy: proxy.map(Arc::new), resolve };
+
+        let mut https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(tls_config)
+            .https_only();
+        if let Some(server_name) = server_name {
+            https = https.with_server_name(server_name.to_string());
+        }
+        let https = https
+            .enable_http1()
+            .enable_http2()
+            .wrap_connector(connector);
+
+        let client = Client::builder()
+            .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+            .build(https);
+
+        Ok(Self { client, url, method })
+    }
+
+    /// 发送 DoH 查询（RFC 8484）
+    ///
+    /// 请求中的消息 ID 置为 0 以便 HTTP 缓存，响应的消息 ID 恢复为原请求 ID
+    pub async fn query(&self, request: &Message, timeout: Duration) -> Result<Message> {
+        let mut message = request.clone();
+        message.set_id(0);
+        let request_data = message.to_vec()?;
+
+        let http_request = match self.method {
+            DohMethod::Get => {
+                use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+                use base64::Engine;
+
+                let separator = if self.url.query().is_some() { '&' } else { '?' };
+                let uri = format!("{}{}dns={}", self.url, separator, URL_SAFE_NO_PAD.encode(&request_data));
+                Request::builder()
+                    .method(Method::GET)
+                    .uri(uri)
+                    .header(hyper::header::ACCEPT, DNS_MESSAGE_MIME)
+                    .body(Body::empty())?
+            }
+            DohMethod::Post => Request::builder()
+                .method(Method::POST)
+                .uri(self.url.clone())
+                .header(hyper::header::ACCEPT, DNS_MESSAGE_MIME)
+                .header(hyper::header::CONTENT_TYPE, DNS_MESSAGE_MIME)
+                .body(Body::from(request_data))?,
+        };
+
+        let response = tokio::time::timeout(timeout, async {
+            let response = self.client.request(http_request).await?;
+            let status = response.status();
+            let version = response.version();
+            let body = hyper::body::to_bytes(response.into_body()).await?;
+            anyhow::Ok((status, version, body))
+        })
+        .await
+        .map_err(|_| anyhow::anyhow!("DoH 请求超时 ({}s)", timeout.as_secs()))??;
+
+        let (status, version, body) = response;
+        if status != StatusCode::OK {
+            anyhow::bail!("DoH 请求失败: HTTP {}", status);
+        }
+        if body.len() > MAX_DNS_MESSAGE_LEN {
+            anyhow::bail!("DoH 响应过大: {} 字节", body.len());
+        }
+
+        let mut response = Message::from_vec(&body)?;
+        response.set_id(request.id());
+        debug!("DoH 收到来自 {} 的响应 ({:?})", self.url, version);
+        Ok(response)
+    }
+}
+
+/// DoH 的 TCP 连接器：配置了代理时经代理连接，否则直连
+#[derive(Clone)]
+struct DohConnector {
+    http: HttpConnector<UpstreamResolver>,
+    proxy: Option<Arc<Proxy>>,
+    resolve: Option<ResolveHost>,
+}
+
+impl hyper::service::Service<Uri> for DohConnector {
+    type Response = TcpStream;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<TcpStream, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let Some(proxy) = self.proxy.clone() else {
+            let connecting = self.http.call(uri);
+            return Box::pin(async move { connecting.await.map_err(Into::into) });
+        };
+
+        // 代理不负责解析时，依次尝试 bootstrap 解析出的每个 IP
+        let host = uri.host().unwrap_or_default().trim_matches(|c| c == '[' || c == ']').to_string();
+        let resolve = self.resolve.clone().filter(|_| !proxy.remote_dns());
+        let port = uri.port_u16().unwrap_or(443);
+        Box::pin(async move {
+            let targets: Vec<String> = match resolve {
+                Some(resolve) => match resolve().await? {
+                    Some(ips) if !ips.is_empty() => ips.iter().map(IpAddr::to_string).collect(),
+                    _ => vec![host],
+                },
+                None => vec![host],
+            };
+            let mut last_error = None;
+            for target in &targets {
+                match proxy.connect(target, port).await {
+                    Ok(stream) => {
+                        stream.set_nodelay(true)?;
+                        return Ok(stream);
+                    }
+                    Err(e) => {
+                        debug!("DoH 经代理连接 {} 失败: {}", target, e);
+                        last_error = Some(e);
+                    }
+                }
+            }
+            Err(last_error.expect("连接目标列表不为空").into())
+        })
+    }
+}
+
+/// DoH 连接使用的解析器：经上游的解析函数（静态 hosts / bootstrap）解析，未得到结果时使用系统 DNS
+#[derive(Clone)]
+struct UpstreamResolver {
+    resolve: Option<ResolveHost>,
+}
+
+impl hyper::service::Service<Name> for UpstreamResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = std::io::Error;
+    type Future = Pin<Box<dyn Future<Output = std::io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolve = self.resolve.clone();
+        Box::pin(async move {
+            let resolved = match resolve {
+                Some(resolve) => resolve().await.map_err(std::io::Error::other)?,
+                None => None,
+            };
+            // 端口由 HttpConnector 按 URL 设置
+            let addrs: Vec<SocketAddr> = match resolved {
+                Some(ips) => ips.iter().map(|ip| SocketAddr::new(*ip, 0)).collect(),
+                None => tokio::net::lookup_host((name.as_str(), 0)).await?.collect(),
+            };
+            Ok(addrs.into_iter())
+        })
+    }
+}
@@ -0,0 +1,243 @@
+use anyhow::Result;
+use hickory_proto::op::Message;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::debug;
+
+use crate::bootstrap::ResolveHost;
+use crate::proxy::Proxy;
+
+/// DNS 消息最大长度
+const MAX_DNS_MESSAGE_LEN: usize = 65535;
+
+/// DoQ 错误码：无错误（RFC 9250 §4.3）
+const DOQ_NO_ERROR: u32 = 0x0;
+
+/// 还有其他地址可尝试时，单个地址的握手超时
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// DoQ 客户端（RFC 9250，每个上游一个）
+///
+/// 复用同一个 QUIC 端点与连接，每个查询使用独立的双向流；连接因空闲超时等原因关闭后
+/// 下次查询自动重新连接，重新连接时使用会话票据尝试 0-RTT。
+/// 配置了 SOCKS5 代理时，每个 QUIC 连接经一个新的 UDP 关联收发数据报。
+/// 每次新建连接时重新解析服务器地址（bootstrap 结果按 TTL 缓存）；服务器有多个地址时按顺序尝试，
+/// 之后优先使用上次连接成功的地址
+pub struct DoqClient {
+    /// 直连时复用的 QUIC 端点（IPv4、IPv6 各一个，按需创建）
+    endpoints: Mutex<[Option<quinn::Endpoint>; 2]>,
+    proxy: Option<Proxy>,
+    client_config: quinn::ClientConfig,
+    /// 服务器域名或 IP 与端口
+    host: String,
+    port: u16,
+    /// 服务器地址解析函数（未得到结果时使用系统 DNS）
+    resolve: ResolveHost,
+    /// 上次连接成功的地址
+    preferred: Mutex<Option<SocketAddr>>,
+    server_name: String,
+    connection: tokio::sync::Mutex<Option<quinn::Connection>>,
+}
+
+impl DoqClient {
+    /// 创建 DoQ 客户端（`resolve` 在每次新建连接时解析 `host`，`server_name` 用于 TLS SNI 与证书校验）
+    pub fn new(
+        host: &str,
+        port: u16,
+        resolve: ResolveHost,
+        server_name: &str,
+        proxy: Option<Proxy>,
+        tls_config: rustls::ClientConfig,
+    ) -> Self {
+        Self {
+            endpoints: Mutex::new([None, None]),
+            proxy,
+            client_config: quinn::ClientConfig::new(Arc::new(tls_config)),
+            host: host.to_string(),
+            port,
+            resolve,
+            preferred: Mutex::new(None),
+            server_name: server_name.to_string(),
+            connection: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// 解析服务器地址：经解析函数（静态 hosts / bootstrap），未得到结果时使用系统 DNS
+    async fn server_addrs(&self) -> Result<Vec<SocketAddr>> {
+        let server_addrs: Vec<SocketAddr> = match (self.resolve)().await? {
+            Some(ips) => ips.into_iter().map(|ip| SocketAddr::new(ip, self.port)).collect(),
+            None => tokio::net::lookup_host((self.host.as_str(), self.port)).await?.collect(),
+        };
+        if server_addrs.is_empty() {
+            anyhow::bail!("DoQ 服务器 {} 没有可用地址", self.host);
+        }
+        Ok(server_addrs)
+    }
+
+    /// 当前是否持有可用连接
+    pub async fn is_connected(&self) -> bool {
+        self.connection.lock().await.as_ref().is_some_and(|c| c.close_reason().is_none())
+    }
+
+    /// 发送 DoQ 查询
+    ///
+    /// 请求的消息 ID 置为 0（RFC 9250 §4.2.1），响应的消息 ID 恢复为原请求 ID。
+    /// 复用的连接已失效或 0-RTT 数据被拒绝时，重新连接后重试一次
+    pub async fn query(&self, request: &Message, timeout: Duration) -> Result<Message> {
+        let mut message = request.clone();
+        message.set_id(0);
+        let request_data = message.to_vec()?;
+
+        let mut framed = Vec::with_capacity(2 + request_data.len());
+        framed.extend_from_slice(&(request_data.len() as u16).to_be_bytes());
+        framed.extend_from_slice(&request_data);
+
+        let mut response = tokio::time::timeout(timeout, async {
+            let (connection, fresh) = self.connection().await?;
+            match Self::exchange(&connection, &framed).await {
+                Ok(response) => Ok(response),
+                Err(e) if !fresh || connection.close_reason().is_some() || is_zero_rtt_rejected(&e) => {
+                    debug!("DoQ 连接 {} 不可用，重新连接: {}", self.server_name, e);
+                    self.reset(&connection).await;
+                    let (connection, _) = self.connection().await?;
+                    Self::exchange(&connection, &framed).await
+                }
+                Err(e) => Err(e),
+            }
+        })
+        .await
+        .map_err(|_| anyhow::anyhow!("DoQ DNS 查询超时 ({}s)", timeout.as_secs()))??;
+
+        response.set_id(request.id());
+        Ok(response)
+    }
+
+    /// 获取可用连接，返回的布尔值表示是否为本次新建的连接
+    async fn connection(&self) -> Result<(quinn::Connection, bool)> {
+        let mut guard = self.connection.lock().await;
+        if let Some(connection) = guard.as_ref() {
+            if connection.close_reason().is_none() {
+                return Ok((connection.clone(), false));
+            }
+            debug!("DoQ 连接 {} 已关闭: {:?}", self.server_name, connection.close_reason());
+        }
+
+        // 从上次连接成功的地址开始依次尝试
+        let server_addrs = self.server_addrs().await?;
+        let preferred = *self.preferred.lock().unwrap();
+        let start = server_addrs.iter().position(|addr| Some(*addr) == preferred).unwrap_or(0);
+        let count = server_addrs.len();
+        let mut last_error = None;
+        for i in 0..count {
+            let server_addr = server_addrs[(start + i) % count];
+            let attempt = self.connect(server_addr);
+            let result = if i + 1 < count {
+                tokio::time::timeout(HANDSHAKE_TIMEOUT, attempt)
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("握手超时")))
+            } else {
+                attempt.await
+            };
+
+            match result {
+                Ok(connection) => {
+                    *self.preferred.lock().unwrap() = Some(server_addr);
+                    *guard = Some(connection.clone());
+                    return Ok((connection, true));
+                }
+                Err(e) => {
+                    debug!("DoQ 连接 {} ({}) 失败: {}", self.server_name, server_addr, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(anyhow::anyhow!("QUIC 连接失败: {}", last_error.expect("服务器地址列表不为空")))
+    }
+
+    /// 连接到指定地址（有会话票据时使用 0-RTT）
+    async fn connect(&self, server_addr: SocketAddr) -> Result<quinn::Connection> {
+        let endpoint = match &self.proxy {
+            Some(proxy) => {
+                let association = proxy.udp_associate().await?;
+                quinn::Endpoint::new_with_abstract_socket(
+                    quinn::EndpointConfig::default(),
+                    None,
+                    association,
+                    Arc::new(quinn::TokioRuntime),
+                )?
+            }
+            None => self.direct_endpoint(server_addr)?,
+        };
+
+        let connecting = endpoint.connect_with(self.client_config.clone(), server_addr, &self.server_name)?;
+        match connecting.into_0rtt() {
+            Ok((connection, _accepted)) => {
+                debug!("DoQ 使用 0-RTT 连接到 {} ({})", self.server_name, server_addr);
+                Ok(connection)
+            }
+            Err(connecting) => {
+                let connection = connecting.await?;
+                debug!("DoQ 已连接到 {} ({})", self.server_name, server_addr);
+                Ok(connection)
+            }
+        }
+    }
+
+    /// 获取与服务器地址族相同的直连端点
+    fn direct_endpoint(&self, server_addr: SocketAddr) -> Result<quinn::Endpoint> {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let slot = &mut endpoints[server_addr.is_ipv6() as usize];
+        if let Some(endpoint) = slot {
+            return Ok(endpoint.clone());
+        }
+
+        let bind_addr: SocketAddr = if server_addr.is_ipv6() {
+            "[::]:0".parse()?
+        } else {
+            "0.0.0.0:0".parse()?
+        };
+        Ok(slot.insert(quinn::Endpoint::client(bind_addr)?).clone())
+    }
+
+    /// 丢弃失效的连接（仅当它仍是当前连接时）
+    async fn reset(&self, connection: &quinn::Connection) {
+        let mut guard = self.connection.lock().await;
+        if guard.as_ref().map(|c| c.stable_id()) == Some(connection.stable_id()) {
+            connection.close(DOQ_NO_ERROR.into(), b"");
+            *guard = None;
+        }
+    }
+
+    /// 在新的双向流上完成一次查询（2 字节长度前缀 + DNS 消息，发送后关闭写端）
+    async fn exchange(connection: &quinn::Connection, framed: &[u8]) -> Result<Message> {
+        let (mut send, mut recv) = connection.open_bi().await?;
+        send.write_all(framed).await?;
+
+        // 关闭写端与读取响应并行进行，无需等待对端确认
+        let (_, data) = tokio::join!(send.finish(), recv.read_to_end(2 + MAX_DNS_MESSAGE_LEN));
+        let data = data?;
+
+        match data.split_first_chunk::<2>() {
+            Some((len_buf, payload)) if u16::from_be_bytes(*len_buf) as usize == payload.len() => {
+                Ok(Message::from_vec(payload)?)
+            }
+            _ => anyhow::bail!("DoQ 响应长度前缀无效"),
+        }
+    }
+}
+
+impl Drop for DoqClient {
+    fn drop(&mut self) {
+        for endpoint in self.endpoints.get_mut().unwrap().iter().flatten() {
+            endpoint.close(DOQ_NO_ERROR.into(), b"");
+        }
+    }
+}
+
+/// 是否为 0-RTT 数据被服务器拒绝导致的错误
+fn is_zero_rtt_rejected(error: &anyhow::Error) -> bool {
+    matches!(error.downcast_ref::<quinn::WriteError>(), Some(quinn::WriteError::ZeroRttRejected))
+        || matches!(error.downcast_ref::<quinn::ReadToEndError>(), Some(quinn::ReadToEndError::Read(quinn::ReadError::ZeroRttRejected)))
+}
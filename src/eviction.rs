@@ -0,0 +1,458 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::config::EvictionPolicy;
+
+/// 空链接
+const NIL: usize = usize::MAX;
+
+/// 访问缓冲区容量；两次回放之间超出容量的访问覆盖最早的记录
+const ACCESS_BUFFER_SIZE: usize = 128;
+
+/// 条目所在的分段
+///
+/// LRU 只使用 `Main`；W-TinyLFU 使用窗口段（`Window`）与主区的试用段（`Main`）、保护段（`Protected`）
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Segment {
+    Window = 0,
+    Main = 1,
+    Protected = 2,
+}
+
+struct Slot<K, V> {
+    entry: Option<(K, V)>,
+    segment: Segment,
+    prev: usize,
+    next: usize,
+}
+
+/// 双向链表（按最近访问排序，头部为最近访问）
+#[derive(Clone, Copy)]
+struct List {
+    head: usize,
+    tail: usize,
+    len: usize,
+}
+
+impl List {
+    const EMPTY: Self = Self { head: NIL, tail: NIL, len: 0 };
+}
+
+/// 容量受限的映射，按配置的淘汰策略在 O(1) 时间内淘汰条目
+///
+/// - `lru`：淘汰最久未访问的条目
+/// - `tinylfu`（W-TinyLFU）：新条目先进入占容量 1% 的窗口段，离开窗口时与主区最久未访问的条目
+///   比较访问频率（Count-Min 草图估算），频率更高者留下；主区分为试用段与保护段（80%），
+///   试用段中再次被访问的条目晋升到保护段
+///
+/// 读取（`peek`）不改变访问顺序；访问记录（`record_access`）只需共享引用，写入无锁缓冲区，
+/// 在写入条目或调用 `flush_accesses` 时（持有独占引用）按顺序回放到访问顺序与频率草图
+pub struct BoundedMap<K, V> {
+    policy: EvictionPolicy,
+    capacity: usize,
+    index: HashMap<K, usize>,
+    slots: Vec<Slot<K, V>>,
+    free: Vec<usize>,
+    lists: [List; 3],
+    sketch: Option<FrequencySketch>,
+    accesses: AccessBuffer,
+    evictions: u64,
+}
+
+impl<K: Eq + Hash + Clone, V> BoundedMap<K, V> {
+    pub fn new(capacity: usize, policy: EvictionPolicy) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            policy,
+            capacity,
+            index: HashMap::new(),
+            slots: Vec::new(),
+            free: Vec::new(),
+            lists: [List::EMPTY; 3],
+            sketch: (policy == EvictionPolicy::TinyLfu).then(|| FrequencySketch::new(capacity)),
+            accesses: AccessBuffer::new(),
+            evictions: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// 累计淘汰的条目数
+    pub fn evictions(&self) -> u64 {
+        self.evictions
+    }
+
+    /// 读取条目，不影响访问顺序（只需共享引用，可在读锁下调用）
+    pub fn peek(&self, key: &K) -> Option<&V> {
+        let idx = *self.index.get(key)?;
+        self.slots[idx].entry.as_ref().map(|(_, value)| value)
+    }
+
+    /// 记录一次访问（只需共享引用，可在读锁下调用），返回条目是否存在
+    pub fn record_access(&self, key: &K) -> bool {
+        match self.index.get(key) {
+            Some(&idx) => {
+                self.accesses.push(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 访问缓冲区是否已满（调用方宜尽快回放，避免覆盖记录）
+    pub fn needs_flush(&self) -> bool {
+        self.accesses.is_full()
+    }
+
+    /// 按顺序回放缓冲区中的访问记录
+    ///
+    /// 槽位只在 `insert` 中复用，而 `insert` 先回放，因此记录中的槽位要么仍是原条目，要么已空
+    pub fn flush_accesses(&mut self) {
+        for idx in self.accesses.drain() {
+            let Some((key, _)) = self.slots[idx].entry.as_ref() else {
+                continue;
+            };
+            if let Some(sketch) = &mut self.sketch {
+                sketch.increment(key);
+            }
+            self.touch(idx);
+        }
+    }
+
+    /// 写入条目，返回因容量限制被淘汰的键（可能是新写入的键本身）
+    ///
+    /// 先回放缓冲的访问记录，淘汰决策基于最新的访问顺序与频率
+    pub fn insert(&mut self, key: K, value: V) -> Option<K> {
+        self.flush_accesses();
+        if let Some(sketch) = &mut self.sketch {
+            sketch.increment(&key);
+        }
+        if let Some(&idx) = self.index.get(&key) {
+            self.slots[idx].entry = Some((key, value));
+            self.touch(idx);
+            return None;
+        }
+
+        let segment = match self.policy {
+            EvictionPolicy::Lru => Segment::Main,
+            EvictionPolicy::TinyLfu => Segment::Window,
+        };
+        let idx = self.alloc(key.clone(), value, segment);
+        self.index.insert(key, idx);
+        self.push_front(idx);
+
+        let evicted = match self.policy {
+            EvictionPolicy::Lru if self.len() > self.capacity => self.lists[Segment::Main as usize].tail,
+            EvictionPolicy::Lru => NIL,
+            EvictionPolicy::TinyLfu => self.tiny_lfu_victim(),
+        };
+        (evicted != NIL).then(|| {
+            self.evictions += 1;
+            self.remove_slot(evicted).0
+        })
+    }
+
+    /// 仅保留满足条件的条目
+    pub fn retain(&mut self, mut keep: impl FnMut(&K, &V) -> bool) {
+        let doomed: Vec<usize> = self.index.values()
+            .copied()
+            .filter(|&idx| self.slots[idx].entry.as_ref().is_some_and(|(key, value)| !keep(key, value)))
+            .collect();
+        for idx in doomed {
+            self.remove_slot(idx);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.slots.iter().filter_map(|slot| slot.entry.as_ref().map(|(key, value)| (key, value)))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    /// 记录一次命中：移到所在分段头部，W-TinyLFU 的试用段条目晋升到保护段
+    fn touch(&mut self, idx: usize) {
+        self.unlink(idx);
+        if self.slots[idx].segment == Segment::Main && self.policy == EvictionPolicy::TinyLfu {
+            self.slots[idx].segment = Segment::Protected;
+            self.push_front(idx);
+
+            // 保护段超出容量时，最久未访问的条目降回试用段
+            let (_, main_capacity) = self.segment_capacities();
+            if self.lists[Segment::Protected as usize].len > main_capacity * 4 / 5 {
+                let demoted = self.lists[Segment::Protected as usize].tail;
+                self.unlink(demoted);
+                self.slots[demoted].segment = Segment::Main;
+                self.push_front(demoted);
+            }
+        } else {
+            self.push_front(idx);
+        }
+    }
+
+    /// W-TinyLFU：窗口段溢出时，窗口中最久未访问的条目进入试用段；
+    /// 总数超出容量时，在它与主区最久未访问的条目中淘汰访问频率较低者
+    fn tiny_lfu_victim(&mut self) -> usize {
+        let (window_capacity, _) = self.segment_capacities();
+        if self.lists[Segment::Window as usize].len <= window_capacity {
+            return NIL;
+        }
+
+        let candidate = self.lists[Segment::Window as usize].tail;
+        self.unlink(candidate);
+        self.slots[candidate].segment = Segment::Main;
+        self.push_front(candidate);
+        if self.len() <= self.capacity {
+            return NIL;
+        }
+
+        let probation_tail = self.lists[Segment::Main as usize].tail;
+        let victim = if probation_tail != candidate {
+            probation_tail
+        } else {
+            self.lists[Segment::Protected as usize].tail
+        };
+        if victim == NIL {
+            return candidate;
+        }
+
+        let frequency = |idx: usize| {
+            let (key, _) = self.slots[idx].entry.as_ref().expect("链表中的槽位都有条目");
+            self.sketch.as_ref().map_or(0, |sketch| sketch.frequency(key))
+        };
+        if frequency(candidate) > frequency(victim) { victim } else { candidate }
+    }
+
+    /// 窗口段与主区的容量
+    fn segment_capacities(&self) -> (usize, usize) {
+        let window = (self.capacity / 100).max(1);
+        (window, self.capacity.saturating_sub(window))
+    }
+
+    fn alloc(&mut self, key: K, value: V, segment: Segment) -> usize {
+        let slot = Slot { entry: Some((key, value)), segment, prev: NIL, next: NIL };
+        match self.free.pop() {
+            Some(idx) => {
+                self.slots[idx] = slot;
+                idx
+            }
+            None => {
+                self.slots.push(slot);
+                self.slots.len() - 1
+            }
+        }
+    }
+
+    fn release(&mut self, idx: usize) -> (K, V) {
+        self.free.push(idx);
+        self.slots[idx].entry.take().expect("释放的槽位必须有条目")
+    }
+
+    fn remove_slot(&mut self, idx: usize) -> (K, V) {
+        self.unlink(idx);
+        let (key, value) = self.release(idx);
+        self.index.remove(&key);
+        (key, value)
+    }
+
+    fn push_front(&mut self, idx: usize) {
+        let list = &mut self.lists[self.slots[idx].segment as usize];
+        let old_head = list.head;
+        list.head = idx;
+        if old_head == NIL {
+            list.tail = idx;
+        }
+        list.len += 1;
+
+        self.slots[idx].prev = NIL;
+        self.slots[idx].next = old_head;
+        if old_head != NIL {
+            self.slots[old_head].prev = idx;
+        }
+    }
+
+    fn unlink(&mut self, idx: usize) {
+        let (prev, next) = (self.slots[idx].prev, self.slots[idx].next);
+        let list = &mut self.lists[self.slots[idx].segment as usize];
+        if prev == NIL { list.head = next } else { self.slots[prev].next = next }
+        if next == NIL { list.tail = prev } else { self.slots[next].prev = prev }
+        list.len -= 1;
+        self.slots[idx].prev = NIL;
+        self.slots[idx].next = NIL;
+    }
+}
+
+/// 无锁访问缓冲区：共享引用下记录被访问的槽位，独占引用下按记录顺序取出
+///
+/// 每条记录为槽位 + 1，0 表示空位
+struct AccessBuffer {
+    entries: Box<[AtomicUsize]>,
+    next: AtomicUsize,
+}
+
+impl AccessBuffer {
+    fn new() -> Self {
+        Self {
+            entries: (0..ACCESS_BUFFER_SIZE).map(|_| AtomicUsize::new(0)).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, idx: usize) {
+        let position = self.next.fetch_add(1, Ordering::Relaxed);
+        self.entries[position % ACCESS_BUFFER_SIZE].store(idx + 1, Ordering::Relaxed);
+    }
+
+    fn is_full(&self) -> bool {
+        self.next.load(Ordering::Relaxed) >= ACCESS_BUFFER_SIZE
+    }
+
+    /// 取出全部记录（从最早的记录开始）并清空缓冲区
+    fn drain(&mut self) -> Vec<usize> {
+        let count = std::mem::take(self.next.get_mut());
+        let start = if count > ACCESS_BUFFER_SIZE { count % ACCESS_BUFFER_SIZE } else { 0 };
+        (0..count.min(ACCESS_BUFFER_SIZE))
+            .map(|i| std::mem::take(self.entries[(start + i) % ACCESS_BUFFER_SIZE].get_mut()))
+            .filter(|&entry| entry != 0)
+            .map(|entry| entry - 1)
+            .collect()
+    }
+}
+
+/// Count-Min 草图：估算键的近期访问频率（4 行，计数上限 15）
+///
+/// 累计计数达到容量的 10 倍时所有计数减半，使频率随时间衰减
+struct FrequencySketch {
+    table: Vec<u8>,
+    mask: usize,
+    additions: usize,
+    sample_size: usize,
+}
+
+impl FrequencySketch {
+    const ROWS: usize = 4;
+    const MAX_COUNT: u8 = 15;
+    const SEEDS: [u64; 4] = [0x9E37_79B9_7F4A_7C15, 0xC2B2_AE3D_27D4_EB4F, 0x1656_67B1_9E37_79F9, 0x27D4_EB2F_1656_67C5];
+
+    fn new(capacity: usize) -> Self {
+        let width = capacity.next_power_of_two().max(16);
+        Self {
+            table: vec![0; width * Self::ROWS],
+            mask: width - 1,
+            additions: 0,
+            sample_size: capacity.saturating_mul(10),
+        }
+    }
+
+    fn slots<K: Hash>(&self, key: &K) -> [usize; 4] {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        let width = self.mask + 1;
+        std::array::from_fn(|row| {
+            let mixed = (hash ^ Self::SEEDS[row]).wrapping_mul(Self::SEEDS[(row + 1) % Self::ROWS]);
+            row * width + ((mixed >> 32) as usize & self.mask)
+        })
+    }
+
+    fn increment<K: Hash>(&mut self, key: &K) {
+        let mut added = false;
+        for slot in self.slots(key) {
+            if self.table[slot] < Self::MAX_COUNT {
+                self.table[slot] += 1;
+                added = true;
+            }
+        }
+        if added {
+            self.additions += 1;
+            if self.additions >= self.sample_size {
+                self.table.iter_mut().for_each(|count| *count /= 2);
+                self.additions /= 2;
+            }
+        }
+    }
+
+    fn frequency<K: Hash>(&self, key: &K) -> u8 {
+        self.slots(key).into_iter().map(|slot| self.table[slot]).min().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut map = BoundedMap::new(3, EvictionPolicy::Lru);
+        for key in 1..=3 {
+            assert_eq!(map.insert(key, key * 10), None);
+        }
+        // 访问 1 后，最久未访问的是 2
+        assert!(map.record_access(&1));
+        assert_eq!(map.insert(4, 40), Some(2));
+        assert_eq!(map.insert(5, 50), Some(3));
+        assert_eq!(map.evictions(), 2);
+        assert_eq!(map.len(), 3);
+
+        let mut keys: Vec<_> = map.iter().map(|(key, _)| *key).collect();
+        keys.sort();
+        assert_eq!(keys, vec![1, 4, 5]);
+
+        map.retain(|key, _| *key == 1);
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.insert(6, 60), None);
+        assert_eq!(map.peek(&6), Some(&60));
+    }
+
+    #[test]
+    fn test_access_buffer() {
+        let mut map = BoundedMap::new(3, EvictionPolicy::Lru);
+        for key in 1..=3 {
+            map.insert(key, ());
+        }
+        // 缓冲区溢出时保留最近的访问记录：最后访问的是 1，最久未访问的是 2
+        for _ in 0..ACCESS_BUFFER_SIZE {
+            assert!(map.record_access(&3));
+        }
+        assert!(map.record_access(&1));
+        assert!(map.needs_flush());
+        assert_eq!(map.insert(4, ()), Some(2));
+        assert!(!map.needs_flush());
+
+        // 已删除条目的访问记录在回放时忽略
+        assert!(map.record_access(&3));
+        map.retain(|key, _| *key != 3);
+        map.insert(5, ());
+        map.flush_accesses();
+        assert_eq!(map.insert(6, ()), Some(1));
+    }
+
+    #[test]
+    fn test_tiny_lfu_keeps_frequent_entries() {
+        let mut map = BoundedMap::new(100, EvictionPolicy::TinyLfu);
+        for key in 0..100 {
+            map.insert(key, ());
+        }
+        for _ in 0..5 {
+            for key in 0..50 {
+                assert!(map.record_access(&key));
+                if map.needs_flush() {
+                    map.flush_accesses();
+                }
+            }
+        }
+
+        // 一次性扫描大量新键，频繁访问的键不应被冲掉
+        for key in 1000..3000 {
+            map.insert(key, ());
+        }
+        assert_eq!(map.len(), 100);
+        assert!((0..50).all(|key| map.peek(&key).is_some()));
+        assert_eq!(map.evictions(), 2000);
+    }
+}
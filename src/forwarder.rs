@@ -1,16 +1,23 @@
-use crate::config::{Config, UpstreamList};
-use crate::cache::{DomainCache, RuleCache};
+use crate::config::{Config, IpPreference, UpstreamList, UpstreamStrategy, UpstreamTlsConfig};
+use crate::bootstrap::{BootstrapCache, ResolveHost};
+use crate::cache::{CacheHit, CacheKey, DomainCache, RuleCache};
+use crate::doh::DohClient;
+use crate::doq::DoqClient;
+use crate::pool::{ConnectionPool, RelayedUdpSession};
+use crate::proxy::Proxy;
+use crate::singleflight::Singleflight;
+use crate::upstream::UpstreamState;
 use anyhow::Result;
-use hickory_proto::op::Message;
-use std::net::SocketAddr;
-use std::time::Duration;
+use hickory_proto::op::{Message, ResponseCode};
+use hickory_proto::rr::{DNSClass, RecordType};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
 use tokio::net::{UdpSocket, TcpStream};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tracing::{debug, warn, info};
-use base64;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
 use rustls::ClientConfig;
-use reqwest::Url;
 
 /// DNS 协议类型
 #[derive(Clone, Debug)]
@@ -23,17 +30,66 @@ pub enum Protocol {
     Rcode(u16), // 特殊协议：返回指定的 RCODE（如 rcode://3 返回 NXDOMAIN）
 }
 
+/// `rcode://` 否定应答中合成 SOA 的 TTL 与 MINIMUM（秒）
+const RCODE_NEGATIVE_TTL: u32 = 300;
+
+/// 每个 DoT 上游的最大连接数
+const DOT_POOL_SIZE: usize = 4;
+
+/// 合并查询的键：查询名（小写）、类型、类别、DO 位、CD 位与上游列表的地址（以逗号连接）
+type FlightKey = (String, RecordType, DNSClass, bool, bool, String);
+
 /// DNS 转发器
 pub struct DnsForwarder {
+    /// 自身的弱引用（供 DoH / DoQ 客户端在新建连接时解析服务器地址）
+    this: Weak<DnsForwarder>,
     config: Config,
     rule_cache: Option<Arc<RuleCache>>,
     domain_cache: Option<Arc<DomainCache>>,
+    upstream_state: UpstreamState,
+    /// DoT 客户端 TLS 配置（按上游复用以支持会话恢复）
+    dot_client_configs: Mutex<HashMap<String, Arc<ClientConfig>>>,
+    /// DoT 连接池（上游地址与代理 -> 连接池）
+    dot_pools: Mutex<HashMap<String, Arc<ConnectionPool>>>,
+    /// 经 SOCKS5 代理的 UDP 会话（上游地址与代理 -> 会话）
+    udp_sessions: Mutex<HashMap<String, Arc<RelayedUdpSession>>>,
+    /// DoH 客户端（上游地址与代理 -> 客户端）
+    doh_clients: Mutex<HashMap<String, Arc<DohClient>>>,
+    /// DoQ 客户端（上游地址与代理 -> 客户端）
+    doq_clients: Mutex<HashMap<String, Arc<DoqClient>>>,
+    /// Bootstrap 解析结果缓存（上游服务器域名 -> IP）
+    bootstrap_cache: BootstrapCache,
+    /// 进行中的上游查询（相同查询合并为一次）
+    inflight: Singleflight<FlightKey, Message>,
 }
 
 impl DnsForwarder {
     /// 创建新的 DNS 转发器
-    pub fn new(config: Config, rule_cache: Option<Arc<RuleCache>>, domain_cache: Option<Arc<DomainCache>>) -> Result<Self> {
-        Ok(Self { config, rule_cache, domain_cache })
+    pub fn new(config: Config, rule_cache: Option<Arc<RuleCache>>, domain_cache: Option<Arc<DomainCache>>) -> Result<Arc<Self>> {
+        // 启动时检查上游 TLS 选项（证书文件、指纹格式等）
+        for (name, upstream_list) in &config.upstreams {
+            if upstream_list.tls != UpstreamTlsConfig::default() {
+                crate::tls::client_config(&[], &upstream_list.tls)
+                    .map_err(|e| anyhow::anyhow!("上游 '{}' 的 TLS 配置无效: {}", name, e))?;
+            }
+        }
+
+        let failure_threshold = config.health_check.enabled.then_some(config.health_check.failure_threshold);
+        let upstream_state = UpstreamState::new(failure_threshold);
+        Ok(Arc::new_cyclic(|this| Self {
+            this: this.clone(),
+            config,
+            rule_cache,
+            domain_cache,
+            upstream_state,
+            dot_client_configs: Mutex::new(HashMap::new()),
+            dot_pools: Mutex::new(HashMap::new()),
+            udp_sessions: Mutex::new(HashMap::new()),
+            doh_clients: Mutex::new(HashMap::new()),
+            doq_clients: Mutex::new(HashMap::new()),
+            bootstrap_cache: BootstrapCache::new(),
+            inflight: Singleflight::new(),
+        }))
     }
 
     /// 解析上游服务器地址
@@ -62,7 +118,7 @@ impl DnsForwarder {
 
     /// 添加转发方法（带监听器名称）
     pub async fn forward_with_listener(
-        &self,
+        self: &Arc<Self>,
         request: &Message,
         listener_name: &str,
     ) -> Result<Message> {
@@ -71,7 +127,7 @@ impl DnsForwarder {
 
     /// 处理UDP请求
     pub async fn handle_udp_request(
-        &self,
+        self: &Arc<Self>,
         socket: &UdpSocket,
         addr: SocketAddr,
         data: &[u8],
@@ -84,7 +140,7 @@ impl DnsForwarder {
     }
 
     /// 处理TCP连接
-    pub async fn handle_tcp_connection(&self, mut socket: TcpStream) -> Result<()> {
+    pub async fn handle_tcp_connection(self: &Arc<Self>, mut socket: TcpStream) -> Result<()> {
         // 读取TCP DNS消息（前2字节是长度）
         let mut len_buf = [0u8; 2];
         socket.read_exact(&mut len_buf).await?;
@@ -106,7 +162,7 @@ impl DnsForwarder {
     }
 
     /// 处理DNS请求
-    async fn process_request(&self, request: &Message, listener_name: Option<&str>) -> Result<Message> {
+    async fn process_request(self: &Arc<Self>, request: &Message, listener_name: Option<&str>) -> Result<Message> {
         let qname = crate::dns::get_qname(request)
             .ok_or_else(|| anyhow::anyhow!("无法获取查询名称"))?;
         
@@ -128,6 +184,10 @@ impl DnsForwarder {
             }
         }
         
+        // 缓存键：查询名、类型、类别与 DO/CD 位
+        let cache_key = CacheKey::from_request(request)
+            .ok_or_else(|| anyhow::anyhow!("无法获取查询名称"))?;
+
         // 2. 查询 Rule Cache（按域名深度匹配）+ Domain Cache（复合KEY查询）
         if let Some(rule_cache) = &self.rule_cache {
             if let Some(domain_cache) = &self.domain_cache {
@@ -136,22 +196,97 @@ impl DnsForwarder {
                 
                 // 遍历匹配项，用复合KEY查询 domain cache
                 for (match_domain, upstream, cache_id) in matches {
-                    if let Some(cached_response) = domain_cache.get_by_key(
+                    match domain_cache.get_by_key(
                         &cache_id, 
                         &match_domain, 
                         &upstream, 
-                        &qname
+                        &cache_key
                     ) {
-                        info!("缓存命中: {} -> {} [KEY: {}|{}|{}]", 
-                            qname, upstream, cache_id, match_domain, upstream);
-                        return Ok(cached_response);
+                        Some(CacheHit::Fresh(cached_response)) => {
+                            let cached_response = crate::dns::response_for_request(request, cached_response);
+                            info!("缓存命中: {} -> {} [KEY: {}|{}|{}]", 
+                                cache_key, upstream, cache_id, match_domain, upstream);
+                            return Ok(cached_response);
+                        }
+                        Some(CacheHit::Stale(stale_response)) => {
+                            let mut stale_response = crate::dns::response_for_request(request, stale_response);
+                            crate::dns::set_extended_error(&mut stale_response, crate::dns::ExtendedError::StaleAnswer);
+                            info!("缓存命中(已过期): {} -> {} [KEY: {}|{}|{}]", 
+                                cache_key, upstream, cache_id, match_domain, upstream);
+                            let (client_timeout, failure_recheck) = domain_cache.serve_stale()
+                                .map_or((0, 0), |stale| (stale.client_timeout, stale.failure_recheck));
+                            return Ok(self.refresh_stale(request, listener_name, cache_key, stale_response, client_timeout, failure_recheck).await);
+                        }
+                        None => {}
                     }
                 }
             }
         }
         
+        self.resolve_uncached(&qname, request, listener_name, cache_key).await
+    }
+
+    /// 过期缓存应答（RFC 8767）：在后台向上游刷新，`client_timeout` 毫秒内刷新成功则返回新结果，
+    /// 否则（超时、上游全部失败或返回 SERVFAIL）返回过期应答，刷新任务继续运行并更新缓存
+    ///
+    /// 刷新失败后 `failure_recheck` 秒内不再刷新该记录，直接返回过期应答，避免每个查询都访问不可用的上游
+    async fn refresh_stale(
+        self: &Arc<Self>,
+        request: &Message,
+        listener_name: Option<&str>,
+        cache_key: CacheKey,
+        stale_response: Message,
+        client_timeout: u64,
+        failure_recheck: u64,
+    ) -> Message {
+        if self.domain_cache.as_ref().is_some_and(|cache| cache.stale_refresh_held(&cache_key)) {
+            debug!("刷新失败后暂停期内，直接返回过期应答: {}", cache_key);
+            return stale_response;
+        }
+
+        let forwarder = Arc::clone(self);
+        let refresh_request = request.clone();
+        let listener_name = listener_name.map(str::to_string);
+        let key = cache_key.clone();
+        let failure_recheck = Duration::from_secs(failure_recheck);
+        let refresh = tokio::spawn(async move {
+            let qname = key.qname.clone();
+            let result = forwarder.resolve_uncached(&qname, &refresh_request, listener_name.as_deref(), key.clone()).await;
+            let failed = match &result {
+                Ok(response) => response.response_code() == ResponseCode::ServFail,
+                Err(e) => {
+                    warn!("刷新过期缓存 {} 失败: {}", qname, e);
+                    true
+                }
+            };
+            if failed {
+                if let Some(cache) = &forwarder.domain_cache {
+                    cache.hold_stale_refresh(&key, failure_recheck);
+                }
+            }
+            result
+        });
+
+        if client_timeout == 0 {
+            return stale_response;
+        }
+        match tokio::time::timeout(Duration::from_millis(client_timeout), refresh).await {
+            Ok(Ok(Ok(response))) if response.response_code() != ResponseCode::ServFail => response,
+            Ok(_) => {
+                info!("上游刷新失败，返回过期应答: {}", cache_key);
+                stale_response
+            }
+            Err(_) => {
+                debug!("等待上游刷新超时 ({}ms)，返回过期应答: {}", client_timeout, cache_key);
+                stale_response
+            }
+        }
+    }
+
+    /// 缓存未命中：按规则选择上游查询，并写入 Rule Cache 与 Domain Cache
+    async fn resolve_uncached(&self, qname: &str, request: &Message, listener_name: Option<&str>, cache_key: CacheKey) -> Result<Message> {
         // 3. 根据域名匹配规则选择上游（缓存未命中时）
-        let (_upstream_list, rule_name, matched_domain, response) = self.match_domain(&qname, request, listener_name).await?;
+        let (_upstream_list, rule_name, matched_domain, response) = self.match_domain(qname, request, listener_name).await?;
         
         // 从 rule_name 中提取 upstream_name
         // rule_name 格式: "group:matched_domain@upstream" 或 "servers:upstream" 或 "final:..."
@@ -181,21 +316,24 @@ impl DnsForwarder {
         }
         
         // 5. 写入 Domain Cache
-        // 注意：servers 规则和 final 规则不参与缓存
+        // 注意：servers 规则和 final 规则不参与缓存；SERVFAIL 等不可缓存的响应不写入，以免覆盖可用于过期应答的记录
         if let Some(cache) = &self.domain_cache {
             if !rule_name.starts_with("servers:") && !rule_name.starts_with("final:") {
-                // 从响应中提取最小 TTL
-                let ttl = self.extract_min_ttl(&response);
-                // Domain Cache 使用匹配到的域名作为规则标识（链接到 rule.cache）
-                let match_domain_str = if matched_domain.is_empty() { ".".to_string() } else { matched_domain.clone() };
-                cache.insert(
-                    qname.clone(),
-                    cache_id.clone(),
-                    match_domain_str,
-                    upstream_list_name.clone(),
-                    response.clone(),
-                    ttl
-                );
+                // 从响应中提取缓存时间（否定应答取 SOA 的 MINIMUM）
+                if let Some(ttl) = crate::dns::response_ttl(&response) {
+                    // Domain Cache 使用匹配到的域名作为规则标识（链接到 rule.cache）
+                    let match_domain_str = if matched_domain.is_empty() { ".".to_string() } else { matched_domain.clone() };
+                    cache.insert(
+                        cache_key,
+                        cache_id.clone(),
+                        match_domain_str,
+                        upstream_list_name.clone(),
+                        response.clone(),
+                        ttl
+                    );
+                } else {
+                    debug!("响应不可缓存: {} ({})", cache_key, response.response_code());
+                }
             }
         }
         
@@ -267,7 +405,7 @@ impl DnsForwarder {
         }
 
         // 如果没有任何上游，返回错误
-        anyhow::bail!("域名 {} 未匹配到任何规则，且没有可用的默认上游", domain)
+        Err(crate::dns::QueryRefused(format!("域名 {} 未匹配到任何规则，且没有可用的默认上游", domain)).into())
     }
 
     /// 匹配服务器规则（按监听器实例）
@@ -472,44 +610,178 @@ impl DnsForwarder {
         }
     }
 
-    /// 从 DNS 响应中提取最小 TTL
-    fn extract_min_ttl(&self, response: &Message) -> u64 {
-        let mut min_ttl = 300; // 默认 5 分钟
+    /// 向指定名称的上游列表发送查询（不经过规则匹配与缓存，用于预取）
+    pub async fn query_upstream(&self, upstream_name: &str, request: &Message) -> Result<Message> {
+        let upstream_list = self.config.upstreams.get(upstream_name)
+            .ok_or_else(|| anyhow::anyhow!("上游 '{}' 不存在", upstream_name))?;
+        self.forward_to_upstream_list(request, upstream_list).await
+    }
 
-        // 从答案、权威和附加记录中提取 TTL
-        for record in response.answers().iter()
-            .chain(response.name_servers().iter())
-            .chain(response.additionals().iter())
-        {
-            let ttl = record.ttl();
-            if ttl > 0 && ttl < min_ttl {
-                min_ttl = ttl;
+    /// 转发到上游列表，合并进行中的相同查询
+    ///
+    /// 同一上游列表上查询名、类型、类别及 DO/CD 位相同的并发查询只向上游发送一次，
+    /// 其余请求等待并复制该结果（按各自的请求改写消息 ID、问题部分与 OPT）
+    async fn forward_to_upstream_list(&self, request: &Message, upstream_list: &UpstreamList) -> Result<Message> {
+        let Some(query) = request.queries().first() else {
+            return self.query_upstream_list(request, upstream_list).await;
+        };
+        let key = (
+            query.name().to_lowercase().to_utf8(),
+            query.query_type(),
+            query.query_class(),
+            request.extensions().as_ref().is_some_and(|edns| edns.dnssec_ok()),
+            request.checking_disabled(),
+            upstream_list.addr.join(","),
+        );
+
+        let (result, shared) = self.inflight
+            .run(key, || self.query_upstream_list(request, upstream_list))
+            .await;
+        if !shared {
+            return result;
+        }
+        debug!("合并查询: {} ({}) 使用进行中查询的结果", query.name(), query.query_type());
+        Ok(crate::dns::response_for_request(request, result?))
+    }
+
+    /// 查询上游列表（按上游的 `strategy` 选择地址）
+    ///
+    /// 所有地址均已熔断时不直接失败，按配置顺序尝试全部地址
+    async fn query_upstream_list(&self, request: &Message, upstream_list: &UpstreamList) -> Result<Message> {
+        if upstream_list.addr.is_empty() {
+            anyhow::bail!("上游列表为空");
+        }
+        let mut addrs = self.upstream_state.order(upstream_list);
+        if addrs.is_empty() {
+            debug!("上游 {:?} 的所有地址均已熔断，尝试全部地址", upstream_list.addr);
+            addrs = upstream_list.addr.iter().map(String::as_str).collect();
+        }
+
+        if upstream_list.strategy == UpstreamStrategy::Parallel && addrs.len() > 1 {
+            return self.forward_parallel(request, upstream_list, &addrs).await;
+        }
+
+        // 按顺序尝试，出错或超时后尝试下一个地址
+        let mut last_error = None;
+        for upstream_addr in addrs {
+            match self.forward_to_upstream(request, upstream_addr, upstream_list).await {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    warn!("上游 {} 查询失败: {}", upstream_addr, e);
+                    last_error = Some(e);
+                }
             }
         }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("上游列表为空")))
+    }
 
-        min_ttl as u64
+    /// 同时查询所有地址，返回第一个有效响应（非 SERVFAIL / REFUSED）
+    ///
+    /// 没有有效响应时返回最先收到的响应，全部失败时返回最后一个错误
+    async fn forward_parallel(&self, request: &Message, upstream_list: &UpstreamList, addrs: &[&str]) -> Result<Message> {
+        use futures::stream::{FuturesUnordered, StreamExt};
+        use hickory_proto::op::ResponseCode;
+
+        let mut pending: FuturesUnordered<_> = addrs.iter()
+            .map(|upstream_addr| async move {
+                (*upstream_addr, self.forward_to_upstream(request, upstream_addr, upstream_list).await)
+            })
+            .collect();
+
+        let mut fallback_response = None;
+        let mut last_error = None;
+        while let Some((upstream_addr, result)) = pending.next().await {
+            match result {
+                Ok(response) if !matches!(response.response_code(), ResponseCode::ServFail | ResponseCode::Refused) => {
+                    debug!("并行查询采用上游 {} 的响应", upstream_addr);
+                    return Ok(response);
+                }
+                Ok(response) => {
+                    debug!("上游 {} 返回 {}，等待其他上游", upstream_addr, response.response_code());
+                    fallback_response.get_or_insert(response);
+                }
+                Err(e) => {
+                    warn!("上游 {} 查询失败: {}", upstream_addr, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        match (fallback_response, last_error) {
+            (Some(response), _) => Ok(response),
+            (None, Some(e)) => Err(e),
+            (None, None) => anyhow::bail!("上游列表为空"),
+        }
     }
 
-    /// 转发到上游列表
-    async fn forward_to_upstream_list(&self, request: &Message, upstream_list: &UpstreamList) -> Result<Message> {
-        // 目前只使用第一个上游地址
-        let upstream_addr = upstream_list.addr.first()
-            .ok_or_else(|| anyhow::anyhow!("上游列表为空"))?;
-        
+    /// 转发到单个上游地址，并记录延迟（失败时按超时时间计入）
+    async fn forward_to_upstream(&self, request: &Message, upstream_addr: &str, upstream_list: &UpstreamList) -> Result<Message> {
         let protocol = Self::parse_protocol(upstream_addr)?;
-        
-        match protocol {
+        let start = Instant::now();
+
+        let result = match protocol {
             Protocol::Rcode(rcode) => {
                 // 特殊协议：直接返回指定的 RCODE 响应
                 debug!("使用 rcode 协议返回 RCODE: {}", rcode);
-                Ok(Self::create_rcode_response(request, rcode))
+                return Ok(Self::create_rcode_response(request, rcode));
+            }
+            Protocol::Udp => self.forward_udp(request, upstream_addr, upstream_list.proxy.as_deref()).await,
+            Protocol::Tcp => self.forward_tcp(request, upstream_addr, upstream_list.proxy.as_deref()).await,
+            Protocol::Dot => self.forward_dot(request, upstream_addr, upstream_list).await,
+            Protocol::Doh => self.forward_doh(request, upstream_addr, upstream_list).await,
+            Protocol::Doq => self.forward_doq(request, upstream_addr, upstream_list).await,
+        };
+
+        match result {
+            Ok(_) => self.upstream_state.record_success(upstream_addr, start.elapsed()),
+            Err(_) => {
+                let penalty = start.elapsed().max(Duration::from_secs(self.config.timeout_secs));
+                self.upstream_state.record_failure(upstream_addr, penalty);
+            }
+        }
+
+        result
+    }
+
+    /// 后台健康检查：按间隔向所有上游地址发送探测查询
+    ///
+    /// 探测结果与普通查询一样计入地址状态，已熔断的地址在探测成功后恢复（半开探测）
+    pub async fn run_health_checks(self: Arc<Self>, probe_query: hickory_proto::op::Query, interval_secs: u64) {
+        use futures::future::join_all;
+
+        // 去重：同一地址只探测一次（rcode:// 无需探测）
+        let mut targets: Vec<(&str, &UpstreamList)> = Vec::new();
+        for upstream_list in self.config.upstreams.values() {
+            for addr in &upstream_list.addr {
+                if !addr.starts_with("rcode://") && !targets.iter().any(|(a, _)| a == addr) {
+                    targets.push((addr, upstream_list));
+                }
+            }
+        }
+        if targets.is_empty() {
+            return;
+        }
+
+        info!("上游健康检查已启动: {} 个地址, 间隔 {} 秒", targets.len(), interval_secs);
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval_secs));
+        loop {
+            ticker.tick().await;
+
+            let mut probe = Message::new();
+            probe.set_id(rand::random());
+            probe.set_recursion_desired(true);
+            probe.add_query(probe_query.clone());
+
+            let results = join_all(targets.iter().map(|(addr, upstream_list)| {
+                self.forward_to_upstream(&probe, addr, upstream_list)
+            }))
+            .await;
+
+            for ((addr, _), result) in targets.iter().zip(results) {
+                if let Err(e) = result {
+                    debug!("上游 {} 健康检查失败: {} ({:?})", addr, e, self.upstream_state.status(addr));
+                }
             }
-            Protocol::Udp => self.forward_udp(request, upstream_addr).await,
-            Protocol::Tcp => self.forward_tcp(request, upstream_addr).await,
-            Protocol::Dot => self.forward_dot(request, upstream_addr, upstream_list.bootstrap.as_ref(), upstream_list.proxy.as_ref()).await,
-            Protocol::Doh => self.forward_doh(request, upstream_addr, upstream_list.bootstrap.as_ref(), upstream_list.proxy.as_ref()).await,
-            // DoQ 基于 UDP，SOCKS5 代理主要支持 TCP，暂不支持
-            Protocol::Doq => self.forward_doq(request, upstream_addr, upstream_list.bootstrap.as_ref()).await,
         }
     }
 
@@ -556,16 +828,8 @@ impl DnsForwarder {
     /// - 4: NOTIMP (未实现)
     /// - 5: REFUSED (拒绝查询)
     fn create_rcode_response(request: &Message, rcode: u16) -> Message {
-        use hickory_proto::op::{ResponseCode, Header, MessageType};
-        
-        let mut response = Message::new();
-        let mut header = Header::new();
-        
-        // 复制请求的 ID
-        header.set_id(request.id());
-        header.set_message_type(MessageType::Response);
-        header.set_op_code(request.op_code());
-        
+        use hickory_proto::op::ResponseCode;
+
         // 设置响应代码
         let response_code = match rcode {
             0 => ResponseCode::NoError,
@@ -576,26 +840,21 @@ impl DnsForwarder {
             5 => ResponseCode::Refused,
             _ => ResponseCode::ServFail, // 未知代码默认为 ServFail
         };
-        header.set_response_code(response_code);
-        
-        // 设置标志
-        header.set_authoritative(false);
-        header.set_recursion_desired(request.recursion_desired());
-        header.set_recursion_available(true);
-        
-        response.set_header(header);
-        
-        // 复制查询部分
-        for query in request.queries() {
-            response.add_query(query.clone());
+        let mut response = crate::dns::rcode_response(request, response_code);
+
+        // NXDOMAIN / NODATA 附带合成的 SOA，下游可按 RFC 2308 缓存
+        if crate::dns::is_negative(&response) {
+            if let Some(query) = request.queries().first() {
+                response.add_name_server(crate::dns::synthetic_soa(query.name().clone(), RCODE_NEGATIVE_TTL));
+            }
         }
-        
+
         debug!("创建 RCODE {} 响应: {}", rcode, response_code);
         response
     }
 
-    /// UDP 转发
-    async fn forward_udp(&self, request: &Message, upstream_addr: &str) -> Result<Message> {
+    /// UDP 转发（配置了 SOCKS5 代理时经 UDP 关联转发）
+    async fn forward_udp(&self, request: &Message, upstream_addr: &str, proxy: Option<&str>) -> Result<Message> {
         // 检查是否为 HTTPS/DoH 地址
         if upstream_addr.starts_with("https://") {
             anyhow::bail!("UDP 转发不支持 HTTPS/DoH 地址: {}", upstream_addr);
@@ -605,37 +864,114 @@ impl DnsForwarder {
         let upstream_socket_addr: SocketAddr = format!("{}:{}", host, port).parse()
             .map_err(|e| anyhow::anyhow!("无法解析地址 '{}:{}': {}", host, port, e))?;
 
-        // 创建 UDP 套接字
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
         let timeout = Duration::from_secs(self.config.timeout_secs);
+        let response = match proxy {
+            Some(proxy_url) => self.forward_udp_relayed(request, upstream_addr, upstream_socket_addr, proxy_url).await?,
+            None => {
+                // 创建 UDP 套接字并发送请求（连接到上游地址，只接收来自上游的数据报）
+                let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                socket.connect(upstream_socket_addr).await?;
+                socket.send(&request.to_vec()?).await?;
+                debug!("UDP 已向 {} 发送 DNS 查询", upstream_addr);
+
+                // 接收响应（按请求通告的 EDNS0 缓冲区大小分配，无 EDNS0 时为 512），忽略消息 ID 不符的数据报
+                let mut buf = vec![0u8; request.max_payload() as usize];
+                let received = tokio::time::timeout(timeout, async {
+                    loop {
+                        let len = socket.recv(&mut buf).await?;
+                        if buf[..len].starts_with(&request.id().to_be_bytes()) {
+                            return Ok::<_, std::io::Error>(len);
+                        }
+                        debug!("丢弃来自 {} 的消息 ID 不符的数据报", upstream_addr);
+                    }
+                })
+                .await;
+                let len = match received {
+                    Ok(Ok(len)) => len,
+                    Ok(Err(e)) => anyhow::bail!("UDP 接收响应失败: {}", e),
+                    Err(_) => anyhow::bail!("UDP DNS 查询超时 ({}s)", self.config.timeout_secs),
+                };
 
-        // 发送请求
-        let request_data = request.to_vec()?;
-        socket.send_to(&request_data, upstream_socket_addr).await?;
-        
-        debug!("UDP 已向 {} 发送 DNS 查询", upstream_addr);
+                // 响应填满缓冲区时可能已被截断（上游未遵守通告的大小），改用 TCP 重试
+                if len == buf.len() {
+                    debug!("UDP 响应超出缓冲区大小 ({} 字节)，改用 TCP 向 {} 重试", len, upstream_addr);
+                    return self.forward_tcp(request, upstream_addr, proxy).await;
+                }
+                Message::from_vec(&buf[..len])?
+            }
+        };
+        debug!("UDP 收到来自 {} 的响应", upstream_addr);
 
-        // 接收响应
-        let mut buf = [0u8; 512];
-        let result = tokio::time::timeout(timeout, socket.recv_from(&mut buf)).await;
+        // 上游响应被截断时改用 TCP 重试
+        if response.truncated() {
+            debug!("UDP 响应被截断 (TC)，改用 TCP 向 {} 重试", upstream_addr);
+            return self.forward_tcp(request, upstream_addr, proxy).await;
+        }
+        Ok(response)
+    }
 
-        match result {
-            Ok(Ok((len, _))) => {
-                let response = Message::from_vec(&buf[..len])?;
-                debug!("UDP 收到来自 {} 的响应", upstream_addr);
-                Ok(response)
-            }
-            Ok(Err(e)) => {
-                anyhow::bail!("UDP 接收响应失败: {}", e);
+    /// 经 SOCKS5 UDP 关联转发
+    ///
+    /// 每个上游与代理复用一个关联，响应按消息 ID 分发；复用的关联失效时重新建立并重试一次
+    async fn forward_udp_relayed(
+        &self,
+        request: &Message,
+        upstream_addr: &str,
+        target: SocketAddr,
+        proxy_url: &str,
+    ) -> Result<Message> {
+        let timeout = Duration::from_secs(self.config.timeout_secs);
+        let key = format!("{} via {}", upstream_addr, proxy_url);
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let (session, reused) = self.udp_session(&key, upstream_addr, target, proxy_url).await?;
+            match session.query(request, timeout).await {
+                Ok(response) => return Ok(response),
+                Err(e) if reused && attempt == 1 && e.downcast_ref::<tokio::time::error::Elapsed>().is_none() => {
+                    debug!("UDP 关联失效，重新通过代理 {} 建立: {}", proxy_url, e);
+                }
+                Err(e) => anyhow::bail!("UDP 经代理查询失败: {}", e),
             }
-            Err(_) => {
-                anyhow::bail!("UDP DNS 查询超时 ({}s)", self.config.timeout_secs);
+        }
+    }
+
+    /// 获取上游的 SOCKS5 UDP 会话，不存在或已失效时建立新的关联
+    ///
+    /// 返回的布尔值表示是否为复用的已有会话
+    async fn udp_session(
+        &self,
+        key: &str,
+        upstream_addr: &str,
+        target: SocketAddr,
+        proxy_url: &str,
+    ) -> Result<(Arc<RelayedUdpSession>, bool)> {
+        if let Some(session) = self.udp_sessions.lock().unwrap().get(key).filter(|session| !session.is_closed()) {
+            return Ok((Arc::clone(session), true));
+        }
+
+        let proxy = Proxy::parse(proxy_url)?;
+        let timeout = Duration::from_secs(self.config.timeout_secs);
+        let association = tokio::time::timeout(timeout, proxy.udp_associate())
+            .await
+            .map_err(|_| anyhow::anyhow!("SOCKS5 代理 {} UDP 关联超时", proxy_url))??;
+        debug!("已通过代理 {} 建立到 {} 的 UDP 关联", proxy_url, upstream_addr);
+        let session = Arc::new(RelayedUdpSession::new(association, target, upstream_addr));
+
+        // 并发建立时保留先放入的有效会话，多余的关联随之关闭
+        let mut sessions = self.udp_sessions.lock().unwrap();
+        match sessions.get(key) {
+            Some(existing) if !existing.is_closed() => Ok((Arc::clone(existing), true)),
+            _ => {
+                sessions.insert(key.to_string(), Arc::clone(&session));
+                Ok((session, false))
             }
         }
     }
 
-    /// TCP 转发
-    async fn forward_tcp(&self, request: &Message, upstream_addr: &str) -> Result<Message> {
+    /// TCP 转发（配置了代理时经代理连接）
+    async fn forward_tcp(&self, request: &Message, upstream_addr: &str, proxy: Option<&str>) -> Result<Message> {
         use tokio::io::{AsyncReadExt, AsyncWriteExt};
         use tokio::net::TcpStream;
 
@@ -645,12 +981,21 @@ impl DnsForwarder {
         }
 
         let (host, port) = Self::parse_address(upstream_addr)?;
-        let upstream_socket_addr: SocketAddr = format!("{}:{}", host, port).parse()
-            .map_err(|e| anyhow::anyhow!("无法解析地址 '{}:{}': {}", host, port, e))?;
+        let timeout = Duration::from_secs(self.config.timeout_secs);
 
         // 连接到上游服务器
-        let mut stream = TcpStream::connect(upstream_socket_addr).await?;
-        let timeout = Duration::from_secs(self.config.timeout_secs);
+        let mut stream = match proxy {
+            Some(proxy_url) => {
+                let proxy = Proxy::parse(proxy_url)?;
+                tokio::time::timeout(timeout, proxy.connect(&host, port)).await
+                    .map_err(|_| anyhow::anyhow!("TCP 代理连接超时"))??
+            }
+            None => {
+                let upstream_socket_addr: SocketAddr = format!("{}:{}", host, port).parse()
+                    .map_err(|e| anyhow::anyhow!("无法解析地址 '{}:{}': {}", host, port, e))?;
+                TcpStream::connect(upstream_socket_addr).await?
+            }
+        };
 
         // 发送长度前缀 + 请求数据
         let request_data = request.to_vec()?;
@@ -666,7 +1011,7 @@ impl DnsForwarder {
         tokio::time::timeout(timeout, stream.read_exact(&mut len_buf)).await??;
         let msg_len = u16::from_be_bytes(len_buf) as usize;
 
-        if msg_len == 0 || msg_len > 4096 {
+        if msg_len == 0 {
             anyhow::bail!("TCP 无效的消息长度: {}", msg_len);
         }
 
@@ -679,15 +1024,91 @@ impl DnsForwarder {
         Ok(response)
     }
 
+    /// 连接池/客户端的索引：同一上游地址经不同代理、使用不同 TLS 选项或不同的服务器地址解析方式
+    /// （`hosts`、`bootstrap`、`ip_preference`）访问时使用不同的连接
+    fn connection_key(upstream_addr: &str, upstream_list: &UpstreamList) -> String {
+        let mut key = upstream_addr.to_string();
+        if let Some(proxy) = &upstream_list.proxy {
+            key.push_str(&format!(" via {}", proxy));
+        }
+        if upstream_list.tls != UpstreamTlsConfig::default() {
+            key.push_str(&format!(" {:?}", upstream_list.tls));
+        }
+        if !upstream_list.hosts.is_empty() {
+            let mut hosts: Vec<_> = upstream_list.hosts.iter().collect();
+            hosts.sort();
+            key.push_str(&format!(" hosts={:?}", hosts));
+        }
+        if let Some(bootstrap) = &upstream_list.bootstrap {
+            key.push_str(&format!(" bootstrap={:?}", bootstrap));
+        }
+        if upstream_list.ip_preference != IpPreference::default() {
+            key.push_str(&format!(" {:?}", upstream_list.ip_preference));
+        }
+        key
+    }
+
+    /// 获取 DoT 客户端 TLS 配置（同一上游复用，重新连接时可恢复 TLS 会话）
+    fn dot_client_config(&self, key: &str, options: &UpstreamTlsConfig) -> Result<Arc<ClientConfig>> {
+        let mut configs = self.dot_client_configs.lock().unwrap();
+        if let Some(config) = configs.get(key) {
+            return Ok(Arc::clone(config));
+        }
+        let config = Arc::new(crate::tls::client_config(&[], options)?);
+        configs.insert(key.to_string(), Arc::clone(&config));
+        Ok(config)
+    }
+
     /// DoT (DNS over TLS) 转发
-    /// DoT (DNS over TLS) 转发
-    async fn forward_dot(&self, request: &Message, upstream_addr: &str, bootstrap: Option<&Vec<String>>, proxy: Option<&String>) -> Result<Message> {
+    ///
+    /// 每个上游维护连接池，连接上按消息 ID 流水线复用；连接失效时重新建立连接并重试一次
+    async fn forward_dot(&self, request: &Message, upstream_addr: &str, upstream_list: &UpstreamList) -> Result<Message> {
         // 提取用户查询的域名（用于日志）
         let query_name = request.queries().first()
             .map(|q| q.name().to_utf8())
             .unwrap_or_else(|| "<unknown>".to_string());
         debug!("[DoT] 开始处理查询: {} -> {}", query_name, upstream_addr);
-        
+
+        let timeout = Duration::from_secs(self.config.timeout_secs);
+        let key = Self::connection_key(upstream_addr, upstream_list);
+        let tls_config = self.dot_client_config(&key, &upstream_list.tls)?;
+        let pool = Arc::clone(
+            self.dot_pools.lock().unwrap()
+                .entry(key)
+                .or_insert_with(|| Arc::new(ConnectionPool::new(upstream_addr, DOT_POOL_SIZE))),
+        );
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let (connection, reused) = pool.get(|| async {
+                tokio::time::timeout(timeout, self.connect_dot(upstream_addr, upstream_list, &tls_config))
+                    .await
+                    .map_err(|_| anyhow::anyhow!("DoT 连接超时"))?
+            })
+            .await?;
+
+            match connection.query(request, timeout).await {
+                Ok(response) => {
+                    debug!("DoT 收到来自 {} 的响应", upstream_addr);
+                    return Ok(response);
+                }
+                // 复用的连接可能已被上游关闭，重新建立连接后重试一次
+                Err(e) if reused && attempt == 1 && e.downcast_ref::<tokio::time::error::Elapsed>().is_none() => {
+                    debug!("DoT 复用连接失败，重新连接 {}: {}", upstream_addr, e);
+                }
+                Err(e) => anyhow::bail!("DoT 查询失败: {}", e),
+            }
+        }
+    }
+
+    /// 建立到 DoT 上游的 TLS 连接
+    async fn connect_dot(
+        &self,
+        upstream_addr: &str,
+        upstream_list: &UpstreamList,
+        tls_config: &Arc<ClientConfig>,
+    ) -> Result<tokio_rustls::client::TlsStream<TcpStream>> {
         // 提取主机名和端口
         let addr_part = upstream_addr.strip_prefix("tls://")
             .ok_or_else(|| anyhow::anyhow!("无效的 DoT 地址"))?
@@ -700,507 +1121,303 @@ impl DnsForwarder {
             (addr_part, 853) // DoT 默认端口
         };
 
-        // 如果配置了 bootstrap DNS，使用 bootstrap 解析域名获取 IP
-        let resolved_host = if let Some(bootstrap_servers) = bootstrap {
-            match self.resolve_with_bootstrap(&host, bootstrap_servers).await {
-                Ok(ips) => {
-                    let ip = ips.first()
-                        .ok_or_else(|| anyhow::anyhow!("Bootstrap 解析未返回 IP 地址"))?;
-                    debug!("[Bootstrap] DoT 服务器 {} -> IP: {}", host, ip);
-                    ip.clone()
-                }
-                Err(e) => {
-                    warn!("DoT Bootstrap DNS 解析失败: {}, 回退到系统 DNS", e);
-                    host.clone()
+        let proxy = upstream_list.proxy.as_deref().map(Proxy::parse).transpose()?;
+
+        // 解析服务器 IP（代理负责解析时跳过），未配置 bootstrap 时交给系统 DNS
+        let targets: Vec<String> = if proxy.as_ref().is_some_and(Proxy::remote_dns) {
+            debug!("DoT 服务器 {} 由代理解析", host);
+            vec![host.clone()]
+        } else {
+            match self.resolve_upstream_host(&host, upstream_list).await? {
+                Some(ips) => {
+                    debug!("[Bootstrap] DoT 服务器 {} -> IP: {:?}", host, ips);
+                    ips.iter().map(IpAddr::to_string).collect()
                 }
+                None => vec![host.clone()],
             }
-        } else {
-            debug!("DoT 未配置 bootstrap DNS，使用系统 DNS 解析");
-            host.clone()
         };
 
-        let timeout = Duration::from_secs(self.config.timeout_secs);
-        let socket_addr = format!("{}:{}", resolved_host, port);
-
-        // 根据是否配置代理，选择连接方式
-        let stream = if let Some(proxy_url) = proxy {
-            // 通过 SOCKS5 代理连接
-            debug!("DoT 通过代理 {} 连接到 {}", proxy_url, socket_addr);
-            
-            // 解析代理地址
-            let proxy_addr = proxy_url
-                .strip_prefix("socks5://")
-                .or_else(|| proxy_url.strip_prefix("socks://"))
-                .unwrap_or(proxy_url);
-            
-            tokio_socks::tcp::Socks5Stream::connect(proxy_addr, socket_addr.as_str())
-                .await
-                .map_err(|e| anyhow::anyhow!("DoT SOCKS5 连接失败: {}", e))?
-                .into_inner()
-        } else {
-            // 直接连接
-            debug!("DoT 直连到 {}", socket_addr);
-            TcpStream::connect(&socket_addr).await?
+        // 依次尝试每个地址，直到连接成功
+        let mut last_error = None;
+        let mut stream = None;
+        for target in &targets {
+            let result = match &proxy {
+                Some(proxy) => proxy.connect(target, port).await,
+                None => {
+                    debug!("DoT 直连到 {}:{}", target, port);
+                    TcpStream::connect((target.as_str(), port)).await.map_err(Into::into)
+                }
+            };
+            match result {
+                Ok(connected) => {
+                    stream = Some(connected);
+                    break;
+                }
+                Err(e) => {
+                    debug!("DoT 连接 {}:{} 失败: {}", target, port, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        let Some(stream) = stream else {
+            return Err(last_error.expect("连接目标列表不为空"));
         };
 
-        let root_store = Self::load_root_certs();
-        let client_config = Arc::new(
-            ClientConfig::builder()
-                .with_safe_defaults()
-                .with_root_certificates(root_store)
-                .with_no_client_auth()
-        );
-
-        // 使用原始主机名作为 SNI（即使连接的是 IP）
-        let server_name = host.as_str().try_into()
-            .map_err(|_| anyhow::anyhow!("无效的服务器名称"))?;
+        // 使用原始主机名（或配置的 SNI）作为 SNI（即使连接的是 IP）
+        let sni = upstream_list.tls.sni.as_deref().unwrap_or(&host);
+        let server_name = sni.try_into()
+            .map_err(|_| anyhow::anyhow!("无效的服务器名称: {}", sni))?;
         
-        if bootstrap.is_some() {
-            debug!("[DoT] 使用 IP 连接，设置 SNI: {}", host);
+        if host.parse::<IpAddr>().is_err() || upstream_list.tls.sni.is_some() {
+            debug!("[DoT] 使用 IP 连接，设置 SNI: {}", sni);
         }
         
-        let connector = tokio_rustls::TlsConnector::from(client_config);
-        let mut tls_stream = connector.connect(server_name, stream).await?;
-        
-        // 发送 DNS 请求（长度前缀 + DNS 消息）
-        let request_data = request.to_vec()?;
-        let len_prefix = (request_data.len() as u16).to_be_bytes();
-        
-        tokio::time::timeout(timeout, tls_stream.write_all(&len_prefix)).await??;
-        tokio::time::timeout(timeout, tls_stream.write_all(&request_data)).await??;
-        
-        debug!("DoT 已向 {} 发送 DNS 查询", upstream_addr);
-
-        // 接收响应
-        let mut len_buf = [0u8; 2];
-        tokio::time::timeout(timeout, tls_stream.read_exact(&mut len_buf)).await??;
-        let msg_len = u16::from_be_bytes(len_buf) as usize;
+        // 复用同一 TLS 配置，重新连接时可恢复 TLS 会话
+        let connector = tokio_rustls::TlsConnector::from(Arc::clone(tls_config));
+        let tls_stream = connector.connect(server_name, stream).await?;
+        Ok(tls_stream)
+    }
 
-        if msg_len == 0 || msg_len > 4096 {
-            anyhow::bail!("DoT 无效的消息长度: {}", msg_len);
+    /// 解析上游服务器域名：IP 地址直接使用，其次为静态 hosts，再次为 bootstrap DNS
+    ///
+    /// 结果按地址族偏好排序；返回 None 表示未配置 bootstrap 或 bootstrap 解析失败，由调用方使用系统 DNS
+    async fn resolve_upstream_host(&self, host: &str, upstream_list: &UpstreamList) -> Result<Option<Vec<IpAddr>>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(Some(vec![ip]));
         }
 
-        let mut buf = vec![0u8; msg_len];
-        tokio::time::timeout(timeout, tls_stream.read_exact(&mut buf)).await??;
+        let ips = if let Some(ips) = upstream_list.hosts.get(host) {
+            debug!("[Bootstrap] 使用静态 IP: {} -> {:?}", host, ips);
+            ips.clone()
+        } else if let Some(bootstrap_servers) = &upstream_list.bootstrap {
+            match self.resolve_with_bootstrap(host, bootstrap_servers).await {
+                Ok(ips) => ips,
+                Err(e) => {
+                    warn!("Bootstrap DNS 解析失败: {}, 回退到系统 DNS", e);
+                    return Ok(None);
+                }
+            }
+        } else {
+            debug!("未配置 bootstrap DNS，使用系统 DNS 解析 {}", host);
+            return Ok(None);
+        };
 
-        let response = Message::from_vec(&buf)?;
-        debug!("DoT 收到来自 {} 的响应", upstream_addr);
-        Ok(response)
+        let ips = crate::bootstrap::apply_preference(ips, upstream_list.ip_preference);
+        if ips.is_empty() {
+            anyhow::bail!("{} 没有符合地址族偏好 {:?} 的 IP 地址", host, upstream_list.ip_preference);
+        }
+        Ok(Some(ips))
     }
 
-    /// 加载根证书
-    fn load_root_certs() -> rustls::RootCertStore {
-        let mut root_store = rustls::RootCertStore::empty();
-        root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
-            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
-                ta.subject,
-                ta.spki,
-                ta.name_constraints,
-            )
-        }));
-        root_store
+    /// 供 DoH / DoQ 客户端在每次新建连接时调用的服务器地址解析函数（经 `resolve_upstream_host`）
+    fn host_resolver(&self, host: &str, upstream_list: &UpstreamList) -> ResolveHost {
+        let this = self.this.clone();
+        let host: Arc<str> = Arc::from(host);
+        let upstream_list = Arc::new(upstream_list.clone());
+        Arc::new(move || {
+            let (this, host, upstream_list) = (this.clone(), Arc::clone(&host), Arc::clone(&upstream_list));
+            Box::pin(async move {
+                let forwarder = this.upgrade().ok_or_else(|| anyhow::anyhow!("转发器已释放"))?;
+                let ips = forwarder.resolve_upstream_host(&host, &upstream_list).await?;
+                if let Some(ips) = &ips {
+                    debug!("[Bootstrap] 上游服务器 {} -> IP: {:?}", host, ips);
+                }
+                Ok(ips)
+            })
+        })
     }
 
-    /// 使用 Bootstrap DNS 解析域名
-    /// 
-    /// 返回解析到的 IP 地址列表
-    async fn resolve_with_bootstrap(&self, domain: &str, bootstrap_servers: &[String]) -> Result<Vec<String>> {
-        use hickory_proto::op::{Message, Query, OpCode};
-        use hickory_proto::rr::{Name, RecordType, RData};
-        use std::str::FromStr;
-        
+    /// 使用 Bootstrap DNS 解析域名（同时查询 A 与 AAAA，结果按 TTL 缓存）
+    ///
+    /// A 或 AAAA 查询失败时结果缺少一个地址族，不写入缓存并继续尝试下一个服务器；
+    /// 所有服务器都只返回部分结果时使用其中第一个（不缓存）。返回解析到的 IP 地址列表
+    async fn resolve_with_bootstrap(&self, domain: &str, bootstrap_servers: &[String]) -> Result<Vec<IpAddr>> {
+        use hickory_proto::rr::RecordType;
+
+        if let Some(ips) = self.bootstrap_cache.get(domain) {
+            debug!("[Bootstrap] 缓存命中: {} -> {:?}", domain, ips);
+            return Ok(ips);
+        }
+
+        let (Some(query_a), Some(query_aaaa)) = (
+            crate::bootstrap::build_query(domain, RecordType::A),
+            crate::bootstrap::build_query(domain, RecordType::AAAA),
+        ) else {
+            anyhow::bail!("Bootstrap 解析: 域名格式错误 '{}'", domain);
+        };
+
         // 尝试每个 bootstrap DNS 服务器
+        let mut partial: Option<Vec<IpAddr>> = None;
         for bootstrap_addr in bootstrap_servers {
             debug!("[Bootstrap] 使用 {} 解析 DNS 服务器域名: {}", bootstrap_addr, domain);
-            
-            // 构造 DNS 查询（A 记录）
-            let domain_name = match Name::from_str(&format!("{}.", domain)) {
-                Ok(name) => name,
-                Err(e) => {
-                    warn!("Bootstrap 解析: 域名格式错误 '{}': {}", domain, e);
-                    continue;
-                }
-            };
-            
-            let mut request = Message::new();
-            request.set_id(rand::random());
-            request.set_op_code(OpCode::Query);
-            request.set_recursion_desired(true);
-            request.add_query(Query::query(domain_name, RecordType::A));
-            
-            // 使用 UDP 查询 bootstrap DNS
-            match self.forward_udp(&request, bootstrap_addr).await {
-                Ok(response) => {
-                    // 提取 A 记录中的 IP 地址
-                    let mut ips = Vec::new();
-                    for answer in response.answers() {
-                        if let Some(RData::A(ipv4)) = answer.data() {
-                            ips.push(ipv4.to_string());
+
+            // 使用 UDP 并行查询 A 与 AAAA 记录
+            let (response_a, response_aaaa) = tokio::join!(
+                self.forward_udp(&query_a, bootstrap_addr, None),
+                self.forward_udp(&query_aaaa, bootstrap_addr, None),
+            );
+
+            let mut ips = Vec::new();
+            let mut min_ttl: Option<Duration> = None;
+            let mut complete = true;
+            for response in [response_a, response_aaaa] {
+                match response {
+                    Ok(response) => {
+                        let (addrs, ttl) = crate::bootstrap::extract_addrs(&response);
+                        ips.extend(addrs);
+                        if let Some(ttl) = ttl {
+                            min_ttl = Some(min_ttl.map_or(ttl, |min| min.min(ttl)));
                         }
                     }
-                    
-                    if !ips.is_empty() {
-                        debug!("Bootstrap DNS 解析成功: {} -> {:?}", domain, ips);
-                        return Ok(ips);
-                    } else {
-                        debug!("Bootstrap DNS {} 未返回 A 记录", bootstrap_addr);
-                        continue;
+                    Err(e) => {
+                        warn!("Bootstrap DNS {} 查询失败: {}", bootstrap_addr, e);
+                        complete = false;
                     }
                 }
-                Err(e) => {
-                    warn!("Bootstrap DNS {} 查询失败: {}", bootstrap_addr, e);
-                    continue;
+            }
+
+            match (ips.is_empty(), min_ttl) {
+                (false, Some(ttl)) if complete => {
+                    debug!("Bootstrap DNS 解析成功: {} -> {:?} (TTL {}s)", domain, ips, ttl.as_secs());
+                    self.bootstrap_cache.insert(domain, ips.clone(), ttl);
+                    return Ok(ips);
+                }
+                (false, Some(_)) => {
+                    debug!("Bootstrap DNS {} 只返回了部分结果: {} -> {:?}，不缓存", bootstrap_addr, domain, ips);
+                    partial.get_or_insert(ips);
                 }
+                _ => debug!("Bootstrap DNS {} 未返回 A/AAAA 记录", bootstrap_addr),
             }
         }
-        
-        anyhow::bail!("所有 Bootstrap DNS 服务器都无法解析域名: {}", domain)
+
+        match partial {
+            Some(ips) => Ok(ips),
+            None => anyhow::bail!("所有 Bootstrap DNS 服务器都无法解析域名: {}", domain),
+        }
     }
 
     /// DoH (DNS over HTTPS) 转发
-    async fn forward_doh(&self, request: &Message, upstream_addr: &str, bootstrap: Option<&Vec<String>>, proxy: Option<&String>) -> Result<Message> {
+    ///
+    /// 每个上游复用一个长期存在的 DoH 客户端（每次新建连接时重新解析服务器地址）；连接层出错时丢弃客户端
+    async fn forward_doh(&self, request: &Message, upstream_addr: &str, upstream_list: &UpstreamList) -> Result<Message> {
         let timeout = Duration::from_secs(self.config.timeout_secs);
-        
+
         // 提取用户查询的域名（用于日志）
         let query_name = request.queries().first()
             .map(|q| q.name().to_utf8())
             .unwrap_or_else(|| "<unknown>".to_string());
         debug!("[DoH] 开始处理查询: {} -> {}", query_name, upstream_addr);
-        
-        // 解析 URL，提取域名和路径
-        let url = upstream_addr.to_string();
-        let (domain, port, path) = {
-            let url_without_scheme = url
-                .strip_prefix("https://")
-                .ok_or_else(|| anyhow::anyhow!("无效的 DoH URL: {}", url))?;
-            
-            let (host_port, path) = url_without_scheme
-                .split_once('/')
-                .map(|(h, p)| (h, format!("/{}", p)))
-                .unwrap_or((url_without_scheme, "/dns-query".to_string()));
-            
-            let (domain, port) = if let Some((d, p)) = host_port.split_once(':') {
-                (d.to_string(), p.parse::<u16>()?)
-            } else {
-                (host_port.to_string(), 443)
-            };
-            
-            (domain, port, path)
-        };
-        
-        // 如果配置了 bootstrap DNS，使用 bootstrap 解析域名并获取 IP 地址
-        let (target_host, original_domain) = if let Some(bootstrap_servers) = bootstrap {
-            match self.resolve_with_bootstrap(&domain, bootstrap_servers).await {
-                Ok(ips) => {
-                    let ip = ips.first()
-                        .ok_or_else(|| anyhow::anyhow!("Bootstrap 解析未返回 IP 地址"))?;
-                    debug!("[Bootstrap] DoH 服务器 {} -> IP: {}", domain, ip);
-                    (ip.clone(), Some(domain.clone())) // 返回 IP 和原始域名
-                }
-                Err(e) => {
-                    warn!("Bootstrap DNS 解析失败: {}, 回退到系统 DNS", e);
-                    (domain.clone(), None) // 使用域名连接，无需特殊处理
-                }
+
+        let key = Self::connection_key(upstream_addr, upstream_list);
+        let cached = self.doh_clients.lock().unwrap().get(&key).cloned();
+        let client = match cached {
+            Some(client) => client,
+            None => {
+                let client = Arc::new(self.create_doh_client(upstream_addr, upstream_list)?);
+                self.doh_clients.lock().unwrap()
+                    .entry(key.clone())
+                    .or_insert(client)
+                    .clone()
             }
-        } else {
-            debug!("DoH 未配置 bootstrap DNS，使用系统 DNS 解析");
-            (domain.clone(), None)
         };
 
-        // 将 DNS 消息编码为 base64
-        let request_data = request.to_vec()?;
-        use base64::engine::general_purpose::URL_SAFE_NO_PAD;
-        use base64::Engine;
-        let dns_query = URL_SAFE_NO_PAD.encode(&request_data);
-
-        // 如果使用 bootstrap，需要手动建立 TLS 连接以控制 SNI
-        if let Some(sni_domain) = original_domain {
-            debug!("[DoH] 使用 bootstrap: 连接到 {}:{}, SNI: {}", target_host, port, sni_domain);
-            
-            // 解析目标地址
-            let socket_addr: SocketAddr = format!("{}:{}", target_host, port).parse()?;
-            
-            // 建立 TCP 连接
-            let stream = tokio::time::timeout(
-                timeout,
-                TcpStream::connect(socket_addr)
-            ).await
-            .map_err(|_| anyhow::anyhow!("连接超时"))??;
-            
-            debug!("[DoH] TCP 连接已建立到 {}", socket_addr);
-            
-            // 配置 TLS
-            let mut root_store = rustls::RootCertStore::empty();
-            root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
-                rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
-                    ta.subject,
-                    ta.spki,
-                    ta.name_constraints,
-                )
-            }));
-            
-            let mut tls_config = ClientConfig::builder()
-                .with_safe_defaults()
-                .with_root_certificates(root_store)
-                .with_no_client_auth();
-            
-            // 设置 ALPN 协议: 优先 HTTP/1.1 (因为我们使用手动 HTTP 实现)
-            tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
-            
-            let tls_config = Arc::new(tls_config);
-            
-            // 创建 TLS 连接器并设置 SNI
-            let connector = tokio_rustls::TlsConnector::from(tls_config);
-            let server_name = rustls::ServerName::try_from(sni_domain.as_str())
-                .map_err(|e| anyhow::anyhow!("无效的服务器名称: {}", e))?;
-            
-            let mut tls_stream = tokio::time::timeout(
-                timeout,
-                connector.connect(server_name, stream)
-            ).await
-            .map_err(|_| anyhow::anyhow!("TLS 握手超时"))??;
-            
-            debug!("[DoH] TLS 握手完成，SNI: {}", sni_domain);
-            
-            // 构建 HTTP/1.1 请求（添加完整的标准 headers）
-            let request_line = format!("GET {}?dns={} HTTP/1.1\r\n", path, dns_query);
-            let headers = format!(
-                "Host: {}\r\n\
-                 Accept: application/dns-message\r\n\
-                 User-Agent: creskyDNS/0.1.1\r\n\
-                 Connection: close\r\n\
-                 \r\n",
-                sni_domain
-            );
-            let http_request = format!("{}{}", request_line, headers);
-            
-            debug!("[DoH] 发送 HTTP 请求: GET {}?dns=...", path);
-            
-            // 发送 HTTP 请求并确保发送完成
-            tls_stream.write_all(http_request.as_bytes()).await?;
-            tls_stream.flush().await?;
-            
-            // 读取 HTTP 响应头
-            let mut response_data = Vec::new();
-            let mut buffer = [0u8; 4096];
-            
-            // 先读取响应头
-            loop {
-                let n = tokio::time::timeout(
-                    timeout,
-                    tls_stream.read(&mut buffer)
-                ).await
-                .map_err(|_| anyhow::anyhow!("读取响应超时"))??;
-                
-                if n == 0 {
-                    break;
-                }
-                
-                response_data.extend_from_slice(&buffer[..n]);
-                
-                // 检查是否已读取完整的 header
-                if response_data.windows(4).any(|w| w == b"\r\n\r\n") {
-                    // 读取完 header,解析 Content-Length
-                    let header_str = String::from_utf8_lossy(&response_data);
-                    
-                    // 查找 header 和 body 的分界点
-                    if let Some(header_end) = response_data.windows(4).position(|w| w == b"\r\n\r\n") {
-                        let body_start = header_end + 4;
-                        let header_part = &response_data[..header_end];
-                        let header_text = String::from_utf8_lossy(header_part);
-                        
-                        // 解析 Content-Length
-                        let mut content_length: Option<usize> = None;
-                        for line in header_text.lines() {
-                            if line.to_lowercase().starts_with("content-length:") {
-                                if let Some(len_str) = line.split(':').nth(1) {
-                                    content_length = len_str.trim().parse().ok();
-                                }
-                            }
-                        }
-                        
-                        // 如果有 Content-Length,读取剩余的 body
-                        if let Some(expected_len) = content_length {
-                            let current_body_len = response_data.len() - body_start;
-                            let remaining = expected_len.saturating_sub(current_body_len);
-                            
-                            if remaining > 0 {
-                                let mut body_buffer = vec![0u8; remaining];
-                                tls_stream.read_exact(&mut body_buffer).await?;
-                                response_data.extend_from_slice(&body_buffer);
-                            }
-                        }
-                        break;
-                    }
-                }
-            }
-            
-            debug!("[DoH] 收到响应，大小: {} 字节", response_data.len());
-            
-            // 解析 HTTP 响应（在字节级别分割 header 和 body）
-            let header_separator = b"\r\n\r\n";
-            let body_start = response_data
-                .windows(header_separator.len())
-                .position(|window| window == header_separator)
-                .ok_or_else(|| anyhow::anyhow!("无效的 HTTP 响应格式：未找到 header 分隔符"))?
-                + header_separator.len();
-            
-            // 提取 header（用于状态码检查）
-            let header_bytes = &response_data[..body_start - header_separator.len()];
-            let header_str = String::from_utf8_lossy(header_bytes);
-            
-            // 检查 HTTP 状态码
-            let status_line = header_str.lines().next()
-                .ok_or_else(|| anyhow::anyhow!("缺少 HTTP 状态行"))?;
-            
-            if !status_line.contains("200") {
-                anyhow::bail!("DoH 请求失败: {}", status_line);
+        let result = client.query(request, timeout).await;
+        if let Err(e) = &result {
+            if e.downcast_ref::<hyper::Error>().is_some() {
+                debug!("[DoH] 连接出错，丢弃 {} 的客户端: {}", upstream_addr, e);
+                self.doh_clients.lock().unwrap().remove(&key);
             }
-            
-            // 提取 body（二进制数据）
-            let body_bytes = &response_data[body_start..];
-            
-            // 解析 DNS 响应（直接从二进制数据）
-            let message = Message::from_vec(body_bytes)?;
-            
-            debug!("DoH 收到来自 {} 的响应", upstream_addr);
-            Ok(message)
-        } else {
-            // 不使用 bootstrap，使用 reqwest 标准方式
-            debug!("[DoH] 标准连接到 {}:{}", domain, port);
-            
-            let client = reqwest::Client::builder()
-                .timeout(timeout)
-                .use_rustls_tls()
-                .build()?;
-            
-            let response = client
-                .get(&url)
-                .query(&[("dns", &dns_query)])
-                .header("Accept", "application/dns-message")
-                .send()
-                .await?;
-            
-            if !response.status().is_success() {
-                anyhow::bail!("DoH 请求失败: HTTP {}", response.status());
-            }
-            
-            let response_data = response.bytes().await?;
-            let message = Message::from_vec(&response_data)?;
-            
-            debug!("DoH 收到来自 {} 的响应", upstream_addr);
-            Ok(message)
         }
+        result
     }
 
-    /// DoQ (DNS over QUIC) 转发
-    async fn forward_doq(&self, request: &Message, upstream_addr: &str, bootstrap: Option<&Vec<String>>) -> Result<Message> {
+    /// 创建 DoH 客户端（每次新建连接时经 bootstrap 解析服务器 IP，配置了代理时经代理连接）
+    fn create_doh_client(&self, upstream_addr: &str, upstream_list: &UpstreamList) -> Result<DohClient> {
+        let domain = upstream_addr.parse::<hyper::Uri>()
+            .ok()
+            .and_then(|uri| uri.host().map(|host| host.trim_matches(|c| c == '[' || c == ']').to_string()))
+            .ok_or_else(|| anyhow::anyhow!("无效的 DoH URL: {}", upstream_addr))?;
+
+        let proxy = upstream_list.proxy.as_deref().map(Proxy::parse).transpose()?;
+
+        // 代理负责解析时不在本地解析服务器 IP
+        let resolve = if proxy.as_ref().is_some_and(Proxy::remote_dns) {
+            debug!("DoH 服务器 {} 由代理解析", domain);
+            None
+        } else {
+            Some(self.host_resolver(&domain, upstream_list))
+        };
+
+        let tls_config = crate::tls::client_config(&[], &upstream_list.tls)?;
+        DohClient::new(upstream_addr, resolve, proxy, upstream_list.tls.sni.as_deref(), upstream_list.doh_method, tls_config)
+    }
+
+    /// DoQ (DNS over QUIC) 转发（RFC 9250）
+    ///
+    /// 每个上游复用一个 DoQ 客户端（QUIC 端点与连接），每个查询使用独立的双向流
+    async fn forward_doq(&self, request: &Message, upstream_addr: &str, upstream_list: &UpstreamList) -> Result<Message> {
+        let timeout = Duration::from_secs(self.config.timeout_secs);
+
         // 提取用户查询的域名（用于日志）
         let query_name = request.queries().first()
             .map(|q| q.name().to_utf8())
             .unwrap_or_else(|| "<unknown>".to_string());
         debug!("[DoQ] 开始处理查询: {} -> {}", query_name, upstream_addr);
-        
-        // 提取主机名和端口
-        let addr_part = upstream_addr.strip_prefix("doq://")
-            .or_else(|| upstream_addr.strip_prefix("quic://"))
-            .ok_or_else(|| anyhow::anyhow!("无效的 DoQ 地址"))?
-            .to_string();
-        
-        let (host, port) = if let Some(colon_pos) = addr_part.rfind(':') {
-            let (h, p) = addr_part.split_at(colon_pos);
-            (h.to_string(), p[1..].parse::<u16>()?)
-        } else {
-            (addr_part, 784) // DoQ 默认端口
-        };
 
-        // 如果配置了 bootstrap DNS，使用 bootstrap 解析域名获取 IP
-        let resolved_host = if let Some(bootstrap_servers) = bootstrap {
-            match self.resolve_with_bootstrap(&host, bootstrap_servers).await {
-                Ok(ips) => {
-                    let ip = ips.first()
-                        .ok_or_else(|| anyhow::anyhow!("Bootstrap 解析未返回 IP 地址"))?;
-                    debug!("[Bootstrap] DoQ 服务器 {} -> IP: {}", host, ip);
-                    ip.clone()
-                }
-                Err(e) => {
-                    warn!("DoQ Bootstrap DNS 解析失败: {}, 回退到系统 DNS", e);
-                    host.clone()
-                }
+        let key = Self::connection_key(upstream_addr, upstream_list);
+        let cached = self.doq_clients.lock().unwrap().get(&key).cloned();
+        let client = match cached {
+            Some(client) => client,
+            None => {
+                let client = Arc::new(self.create_doq_client(upstream_addr, upstream_list)?);
+                self.doq_clients.lock().unwrap()
+                    .entry(key.clone())
+                    .or_insert(client)
+                    .clone()
             }
-        } else {
-            debug!("DoQ 未配置 bootstrap DNS，使用系统 DNS 解析");
-            host.clone()
         };
 
-        let timeout = Duration::from_secs(self.config.timeout_secs);
-        let socket_addr = format!("{}:{}", resolved_host, port).parse::<SocketAddr>()?;
-
-        // 创建 QUIC 客户端配置
-        let client_config = quinn::ClientConfig::with_native_roots();
-
-        let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
-        socket.set_nonblocking(true)?;
-        let mut endpoint = quinn::Endpoint::new(
-            Default::default(),
-            None,
-            socket,
-            Arc::new(quinn::TokioRuntime),
-        )?;
-        endpoint.set_default_client_config(client_config);
-
-        // 连接到 QUIC 服务器（使用原始域名作为 SNI）
-        if bootstrap.is_some() {
-            debug!("[DoQ] 使用 IP {} 连接，设置 SNI: {}", resolved_host, host);
+        let result = client.query(request, timeout).await;
+        match &result {
+            Ok(_) => debug!("DoQ 收到来自 {} 的响应", upstream_addr),
+            // 无法建立连接时丢弃客户端，下次查询重新解析服务器地址
+            Err(e) if !client.is_connected().await => {
+                debug!("[DoQ] 连接不可用，丢弃 {} 的客户端: {}", upstream_addr, e);
+                let mut clients = self.doq_clients.lock().unwrap();
+                if clients.get(&key).is_some_and(|cached| Arc::ptr_eq(cached, &client)) {
+                    clients.remove(&key);
+                }
+            }
+            Err(_) => {}
         }
-        
-        let connection = endpoint
-            .connect(socket_addr, &host)?
-            .await
-            .map_err(|e| anyhow::anyhow!("QUIC 连接失败: {}", e))?;
-
-        // 打开单向流发送 DNS 查询
-        let mut send = connection
-            .open_uni()
-            .await
-            .map_err(|e| anyhow::anyhow!("打开 QUIC 流失败: {}", e))?;
+        result
+    }
 
-        let request_data = request.to_vec()?;
-        send.write_all(&request_data)
-            .await
-            .map_err(|e| anyhow::anyhow!("写入 QUIC 数据失败: {}", e))?;
+    /// 创建 DoQ 客户端（每次新建连接时经 bootstrap 解析服务器 IP，SNI 仍使用原始域名）
+    ///
+    /// 经 SOCKS5 代理时 QUIC 需要目标 IP，服务器域名总在本地解析
+    fn create_doq_client(&self, upstream_addr: &str, upstream_list: &UpstreamList) -> Result<DoqClient> {
+        let proxy = upstream_list.proxy.as_deref().map(Proxy::parse).transpose()?;
 
-        send.finish()
-            .await
-            .map_err(|e| anyhow::anyhow!("关闭 QUIC 发送流失败: {}", e))?;
+        // 提取主机名和端口
+        let addr_part = upstream_addr.strip_prefix("doq://")
+            .or_else(|| upstream_addr.strip_prefix("quic://"))
+            .ok_or_else(|| anyhow::anyhow!("无效的 DoQ 地址"))?;
 
-        debug!("DoQ 已向 {} 发送 DNS 查询", upstream_addr);
+        let (host, port) = match addr_part.rsplit_once(':') {
+            Some((h, p)) if !h.is_empty() && (!h.contains(':') || h.ends_with(']')) => (h, p.parse::<u16>()?),
+            _ => (addr_part, 853), // DoQ 默认端口（RFC 9250）
+        };
+        let host = host.trim_matches(|c| c == '[' || c == ']').to_string();
 
-        // 通过双向流接收响应
-        let (_send, mut recv) = connection
-            .open_bi()
-            .await
-            .map_err(|e| anyhow::anyhow!("打开 QUIC 双向流失败: {}", e))?;
+        let resolve = self.host_resolver(&host, upstream_list);
 
-        let _response_data: Vec<u8> = Vec::new();
-        let result = tokio::time::timeout(
-            timeout,
-            recv.read_to_end(4096)
-        ).await;
+        // 启用 0-RTT：重新连接时使用会话票据直接发送查询
+        let mut tls_config = crate::tls::client_config(&[b"doq"], &upstream_list.tls)?;
+        tls_config.enable_early_data = true;
 
-        match result {
-            Ok(Ok(data)) => {
-                let message = Message::from_vec(&data)?;
-                debug!("DoQ 收到来自 {} 的响应", upstream_addr);
-                Ok(message)
-            }
-            Ok(Err(e)) => {
-                anyhow::bail!("DoQ 接收响应失败: {}", e);
-            }
-            Err(_) => {
-                anyhow::bail!("DoQ DNS 查询超时 ({}s)", self.config.timeout_secs);
-            }
-        }
+        let server_name = upstream_list.tls.sni.as_deref().unwrap_or(&host);
+        Ok(DoqClient::new(&host, port, resolve, server_name, proxy, tls_config))
     }
 
     /// 处理 Final 规则
@@ -0,0 +1,833 @@
+use anyhow::Result;
+use hickory_proto::op::{Message, MessageType, OpCode, ResponseCode};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
+
+use crate::config::{ListenerConfig, ListenerType};
+use crate::dns::{self, ExtendedError, QueryRefused};
+use crate::forwarder::DnsForwarder;
+
+/// TLS 握手超时
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// DoH 消息的 MIME 类型
+const DNS_MESSAGE_MIME: &str = "application/dns-message";
+
+/// DoH 请求体最大长度（DNS 消息上限）
+const MAX_DNS_MESSAGE_LEN: usize = 65535;
+
+/// UDP 响应大小上限（即使客户端通告了更大的 EDNS0 缓冲区）
+const MAX_UDP_RESPONSE_SIZE: u16 = 4096;
+
+/// TCP / DoT 连接空闲超时（RFC 7766 §6.2.3）
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 单个 TCP / DoT 连接上同时处理（含等待写回）的查询数上限
+const TCP_MAX_PENDING_QUERIES: usize = 64;
+
+/// DoQ 连接空闲超时
+const DOQ_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// DoQ 错误码（RFC 9250 §4.3）
+const DOQ_PROTOCOL_ERROR: u32 = 0x2;
+
+/// 各监听器类型对应的 ALPN 协议
+fn alpn_protocols(listener_type: ListenerType) -> &'static [&'static [u8]] {
+    match listener_type {
+        ListenerType::Dns => &[],
+        ListenerType::Dot => &[b"dot"],
+        ListenerType::Doh => &[b"h2", b"http/1.1"],
+        ListenerType::Doq => &[b"doq"],
+    }
+}
+
+/// 运行单个监听器（每个绑定地址按类型启动 UDP/TCP、DoT、DoH 或 DoQ）
+pub async fn run_listener(
+    name: String,
+    listener_config: ListenerConfig,
+    forwarder: Arc<DnsForwarder>,
+) -> Result<()> {
+    let addrs = listener_config.socket_addrs()?;
+    let mut handles = Vec::new();
+
+    // 加密监听器：加载证书（所有绑定地址共享）
+    let tls_config = match (&listener_config.cert, &listener_config.key) {
+        (Some(cert), Some(key)) if listener_config.uses_tls() => {
+            let alpn = alpn_protocols(listener_config.r#type);
+            let config = if listener_config.r#type == ListenerType::Doq {
+                crate::tls::quic_server_config(cert, key, alpn)?
+            } else {
+                crate::tls::server_config(cert, key, alpn)?
+            };
+            info!("监听器 '{}' 已加载证书: {}", name, cert);
+            Some(config)
+        }
+        _ => None,
+    };
+
+    for addr in addrs {
+        match listener_config.r#type {
+            ListenerType::Dns => {
+                info!("监听器 '{}' 启动在 {} (UDP/TCP)", name, addr);
+
+                // 启动 UDP 监听器
+                let udp_forwarder = Arc::clone(&forwarder);
+                let udp_name = format!("{}-udp", &name);
+                let name_for_udp = name.clone();
+                handles.push(tokio::spawn(async move {
+                    if let Err(e) = run_udp_listener(udp_name, addr, udp_forwarder, name_for_udp).await {
+                        error!("UDP 监听器 {} 错误: {}", addr, e);
+                    }
+                }));
+
+                // 启动 TCP 监听器
+                let tcp_forwarder = Arc::clone(&forwarder);
+                let tcp_name = format!("{}-tcp", &name);
+                let name_for_tcp = name.clone();
+                handles.push(tokio::spawn(async move {
+                    if let Err(e) = run_tcp_listener(tcp_name, addr, tcp_forwarder, name_for_tcp).await {
+                        error!("TCP 监听器 {} 错误: {}", addr, e);
+                    }
+                }));
+            }
+            ListenerType::Dot => {
+                info!("监听器 '{}' 启动在 {} (DoT)", name, addr);
+
+                let tls_config = tls_config.clone()
+                    .ok_or_else(|| anyhow::anyhow!("DoT 监听器 '{}' 缺少证书配置", name))?;
+                let dot_forwarder = Arc::clone(&forwarder);
+                let dot_name = format!("{}-dot", &name);
+                let name_for_dot = name.clone();
+                handles.push(tokio::spawn(async move {
+                    let acceptor = TlsAcceptor::from(tls_config);
+                    if let Err(e) = run_dot_listener(dot_name, addr, acceptor, dot_forwarder, name_for_dot).await {
+                        error!("DoT 监听器 {} 错误: {}", addr, e);
+                    }
+                }));
+            }
+            ListenerType::Doh => {
+                let scheme = if tls_config.is_some() { "HTTPS" } else { "HTTP" };
+                info!("监听器 '{}' 启动在 {} (DoH/{})", name, addr, scheme);
+
+                let context = Arc::new(DohContext {
+                    forwarder: Arc::clone(&forwarder),
+                    paths: listener_config.doh_paths(&name),
+                });
+                for (path, route) in &context.paths {
+                    debug!("DoH 监听器 '{}' 路径 {} -> 监听器 '{}'", name, path, route);
+                }
+                let acceptor = tls_config.clone().map(TlsAcceptor::from);
+                let doh_name = format!("{}-doh", &name);
+                handles.push(tokio::spawn(async move {
+                    if let Err(e) = run_doh_listener(doh_name, addr, acceptor, context).await {
+                        error!("DoH 监听器 {} 错误: {}", addr, e);
+                    }
+                }));
+            }
+            ListenerType::Doq => {
+                info!("监听器 '{}' 启动在 {} (DoQ)", name, addr);
+
+                let tls_config = tls_config.clone()
+                    .ok_or_else(|| anyhow::anyhow!("DoQ 监听器 '{}' 缺少证书配置", name))?;
+                let doq_forwarder = Arc::clone(&forwarder);
+                let doq_name = format!("{}-doq", &name);
+                let name_for_doq = name.clone();
+                handles.push(tokio::spawn(async move {
+                    if let Err(e) = run_doq_listener(doq_name, addr, tls_config, doq_forwarder, name_for_doq).await {
+                        error!("DoQ 监听器 {} 错误: {}", addr, e);
+                    }
+                }));
+            }
+        }
+    }
+
+    // 等待所有监听任务
+    for handle in handles {
+        if let Err(e) = handle.await {
+            error!("监听器 '{}' 任务失败: {}", name, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// 创建 socket2 套接字（IPv6 地址仅监听 IPv6，双栈需同时配置 IPv4 与 IPv6 地址）
+fn new_socket(addr: SocketAddr, ty: socket2::Type, protocol: socket2::Protocol) -> Result<socket2::Socket> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), ty, Some(protocol))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// 绑定 UDP 套接字
+pub fn bind_udp_socket(addr: SocketAddr) -> Result<UdpSocket> {
+    Ok(UdpSocket::from_std(bind_std_udp_socket(addr)?)?)
+}
+
+/// 绑定标准库 UDP 套接字（供 QUIC 使用）
+fn bind_std_udp_socket(addr: SocketAddr) -> Result<std::net::UdpSocket> {
+    let socket = new_socket(addr, socket2::Type::DGRAM, socket2::Protocol::UDP)?;
+    socket.bind(&addr.into())
+        .map_err(|e| anyhow::anyhow!("无法绑定 UDP {}: {}", addr, e))?;
+    Ok(socket.into())
+}
+
+/// 绑定 TCP 监听套接字
+pub fn bind_tcp_listener(addr: SocketAddr) -> Result<TcpListener> {
+    let socket = new_socket(addr, socket2::Type::STREAM, socket2::Protocol::TCP)?;
+    // 与 tokio 保持一致：非 Windows 平台允许快速重启后重新绑定
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())
+        .map_err(|e| anyhow::anyhow!("无法绑定 TCP {}: {}", addr, e))?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// 运行 UDP 监听器
+async fn run_udp_listener(
+    name: String,
+    listen_addr: SocketAddr,
+    forwarder: Arc<DnsForwarder>,
+    listener_name: String,
+) -> Result<()> {
+    let socket = bind_udp_socket(listen_addr)?;
+    let socket = Arc::new(socket);
+
+    debug!("UDP 监听器 '{}' 绑定到 {}", name, listen_addr);
+
+    let log_name = name.clone();
+    // 接收缓冲区只分配一次，每个查询只复制实际收到的数据
+    let mut buf = vec![0u8; MAX_DNS_MESSAGE_LEN];
+    // 处理 DNS 查询
+    loop {
+        match socket.recv_from(&mut buf).await {
+            Ok((len, peer_addr)) => {
+                let socket = Arc::clone(&socket);
+                let forwarder = Arc::clone(&forwarder);
+                let data = buf[..len].to_vec();
+                let listener_name = listener_name.clone();
+                let log_name = log_name.clone();
+
+                tokio::spawn(async move {
+                    if let Err(e) = handle_udp_query(socket, forwarder, peer_addr, data, listener_name).await {
+                        error!("UDP 监听器 '{}' 处理查询失败 [{}]: {}", log_name, peer_addr, e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("UDP 监听器 '{}' 接收数据失败: {}", name, e);
+            }
+        }
+    }
+}
+
+/// 运行 TCP 监听器
+async fn run_tcp_listener(
+    name: String,
+    listen_addr: SocketAddr,
+    forwarder: Arc<DnsForwarder>,
+    listener_name: String,
+) -> Result<()> {
+    let listener = bind_tcp_listener(listen_addr)?;
+
+    debug!("TCP 监听器 '{}' 绑定到 {}", name, listen_addr);
+
+    loop {
+        match listener.accept().await {
+            Ok((socket, peer_addr)) => {
+                let forwarder = Arc::clone(&forwarder);
+                let listener_name = listener_name.clone();
+
+                tokio::spawn(async move {
+                    if let Err(e) = handle_tcp_connection(socket, forwarder, peer_addr, listener_name).await {
+                        error!("TCP 连接处理失败 [{}]: {}", peer_addr, e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("TCP 监听器 '{}' 接受连接失败: {}", name, e);
+            }
+        }
+    }
+}
+
+/// 运行 DoT 监听器（RFC 7858：TLS 之上使用与 TCP 相同的长度前缀格式）
+async fn run_dot_listener(
+    name: String,
+    listen_addr: SocketAddr,
+    acceptor: TlsAcceptor,
+    forwarder: Arc<DnsForwarder>,
+    listener_name: String,
+) -> Result<()> {
+    let listener = bind_tcp_listener(listen_addr)?;
+
+    debug!("DoT 监听器 '{}' 绑定到 {}", name, listen_addr);
+
+    loop {
+        match listener.accept().await {
+            Ok((socket, peer_addr)) => {
+                let acceptor = acceptor.clone();
+                let forwarder = Arc::clone(&forwarder);
+                let listener_name = listener_name.clone();
+
+                tokio::spawn(async move {
+                    let tls_stream = match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+                        Ok(Ok(stream)) => stream,
+                        Ok(Err(e)) => {
+                            debug!("DoT TLS 握手失败 [{}]: {}", peer_addr, e);
+                            return;
+                        }
+                        Err(_) => {
+                            debug!("DoT TLS 握手超时 [{}]", peer_addr);
+                            return;
+                        }
+                    };
+
+                    if let Err(e) = handle_tcp_connection(tls_stream, forwarder, peer_addr, listener_name).await {
+                        error!("DoT 连接处理失败 [{}]: {}", peer_addr, e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("DoT 监听器 '{}' 接受连接失败: {}", name, e);
+            }
+        }
+    }
+}
+
+/// DoH 监听器共享状态
+struct DohContext {
+    forwarder: Arc<DnsForwarder>,
+    /// 路径 -> 用于规则匹配的监听器名称
+    paths: HashMap<String, String>,
+}
+
+/// 运行 DoH 监听器（RFC 8484，`acceptor` 为空时使用明文 HTTP）
+async fn run_doh_listener(
+    name: String,
+    listen_addr: SocketAddr,
+    acceptor: Option<TlsAcceptor>,
+    context: Arc<DohContext>,
+) -> Result<()> {
+    let listener = bind_tcp_listener(listen_addr)?;
+
+    debug!("DoH 监听器 '{}' 绑定到 {}", name, listen_addr);
+
+    loop {
+        match listener.accept().await {
+            Ok((socket, peer_addr)) => {
+                let acceptor = acceptor.clone();
+                let context = Arc::clone(&context);
+
+                tokio::spawn(async move {
+                    let result = match acceptor {
+                        Some(acceptor) => {
+                            match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+                                Ok(Ok(stream)) => serve_doh_connection(stream, peer_addr, context).await,
+                                Ok(Err(e)) => {
+                                    debug!("DoH TLS 握手失败 [{}]: {}", peer_addr, e);
+                                    return;
+                                }
+                                Err(_) => {
+                                    debug!("DoH TLS 握手超时 [{}]", peer_addr);
+                                    return;
+                                }
+                            }
+                        }
+                        None => serve_doh_connection(socket, peer_addr, context).await,
+                    };
+
+                    if let Err(e) = result {
+                        debug!("DoH 连接结束 [{}]: {}", peer_addr, e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("DoH 监听器 '{}' 接受连接失败: {}", name, e);
+            }
+        }
+    }
+}
+
+/// 在单个连接上提供 HTTP/1.1 或 HTTP/2 服务
+async fn serve_doh_connection<S>(stream: S, peer_addr: SocketAddr, context: Arc<DohContext>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = hyper::service::service_fn(move |request| {
+        let context = Arc::clone(&context);
+        async move { Ok::<_, Infallible>(handle_doh_request(request, peer_addr, context).await) }
+    });
+
+    hyper::server::conn::Http::new()
+        .serve_connection(stream, service)
+        .await?;
+    Ok(())
+}
+
+/// 处理单个 DoH 请求
+async fn handle_doh_request(
+    request: hyper::Request<hyper::Body>,
+    peer_addr: SocketAddr,
+    context: Arc<DohContext>,
+) -> hyper::Response<hyper::Body> {
+    use hyper::{Method, StatusCode};
+
+    let listener_name = match context.paths.get(request.uri().path()) {
+        Some(name) => name.clone(),
+        None => return doh_error_response(StatusCode::NOT_FOUND),
+    };
+
+    // 读取 DNS 消息：GET ?dns=<base64url> 或 POST application/dns-message
+    let query_data = match *request.method() {
+        Method::GET => match read_doh_get_query(request.uri().query()) {
+            Some(data) => data,
+            None => return doh_error_response(StatusCode::BAD_REQUEST),
+        },
+        Method::POST => {
+            let content_type = request.headers()
+                .get(hyper::header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("");
+            if !content_type.starts_with(DNS_MESSAGE_MIME) {
+                return doh_error_response(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            match read_doh_post_body(request.into_body()).await {
+                Ok(data) => data,
+                Err(status) => return doh_error_response(status),
+            }
+        }
+        _ => return doh_error_response(StatusCode::METHOD_NOT_ALLOWED),
+    };
+
+    let dns_request = match Message::from_vec(&query_data) {
+        Ok(message) => message,
+        Err(e) => {
+            debug!("DoH 请求解析失败 [{}]: {}", peer_addr, e);
+            return doh_error_response(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    debug!("收到来自 {} 的 DoH DNS 查询 (监听器: {})", peer_addr, listener_name);
+    for query in dns_request.queries() {
+        debug!("  查询: {} ({})", query.name(), query.query_type());
+    }
+
+    let response = match answer_query(&context.forwarder, &dns_request, &listener_name, peer_addr).await {
+        Some(response) => response,
+        None => return doh_error_response(StatusCode::BAD_REQUEST),
+    };
+
+    let response_data = match response.to_vec() {
+        Ok(data) => data,
+        Err(e) => {
+            error!("DoH 响应编码失败 [{}]: {}", peer_addr, e);
+            return doh_error_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let mut builder = hyper::Response::builder()
+        .status(StatusCode::OK)
+        .header(hyper::header::CONTENT_TYPE, DNS_MESSAGE_MIME);
+    // RFC 8484 §5.1：缓存时间不超过响应中的最小 TTL
+    if let Some(ttl) = response.answers().iter()
+        .chain(response.name_servers().iter())
+        .map(|record| record.ttl())
+        .min()
+    {
+        builder = builder.header(hyper::header::CACHE_CONTROL, format!("max-age={}", ttl));
+    }
+
+    debug!("DoH 响应已发送至 {}", peer_addr);
+    builder.body(hyper::Body::from(response_data))
+        .unwrap_or_else(|_| doh_error_response(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+/// 从 GET 查询字符串中解析 `dns` 参数（base64url，无填充）
+fn read_doh_get_query(query: Option<&str>) -> Option<Vec<u8>> {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+
+    let encoded = query?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("dns="))?;
+    // 兼容带填充的客户端
+    URL_SAFE_NO_PAD.decode(encoded.trim_end_matches('=')).ok()
+}
+
+/// 读取 POST 请求体（限制最大长度）
+async fn read_doh_post_body(mut body: hyper::Body) -> std::result::Result<Vec<u8>, hyper::StatusCode> {
+    use hyper::body::HttpBody;
+
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| hyper::StatusCode::BAD_REQUEST)?;
+        if data.len() + chunk.len() > MAX_DNS_MESSAGE_LEN {
+            return Err(hyper::StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// 构造 DoH 错误响应
+fn doh_error_response(status: hyper::StatusCode) -> hyper::Response<hyper::Body> {
+    let mut response = hyper::Response::new(hyper::Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+/// 运行 DoQ 监听器（RFC 9250）
+async fn run_doq_listener(
+    name: String,
+    listen_addr: SocketAddr,
+    tls_config: Arc<rustls::ServerConfig>,
+    forwarder: Arc<DnsForwarder>,
+    listener_name: String,
+) -> Result<()> {
+    let mut transport = quinn::TransportConfig::default();
+    transport.max_idle_timeout(Some(DOQ_IDLE_TIMEOUT.try_into()?));
+    // DoQ 只使用双向流
+    transport.max_concurrent_uni_streams(0u32.into());
+
+    let mut server_config = quinn::ServerConfig::with_crypto(tls_config);
+    server_config.transport_config(Arc::new(transport));
+
+    let socket = bind_std_udp_socket(listen_addr)?;
+    let endpoint = quinn::Endpoint::new(
+        quinn::EndpointConfig::default(),
+        Some(server_config),
+        socket,
+        Arc::new(quinn::TokioRuntime),
+    )?;
+
+    debug!("DoQ 监听器 '{}' 绑定到 {}", name, listen_addr);
+
+    while let Some(connecting) = endpoint.accept().await {
+        let forwarder = Arc::clone(&forwarder);
+        let listener_name = listener_name.clone();
+
+        tokio::spawn(async move {
+            let peer_addr = connecting.remote_address();
+            match connecting.await {
+                Ok(connection) => handle_doq_connection(connection, forwarder, listener_name).await,
+                Err(e) => debug!("DoQ 握手失败 [{}]: {}", peer_addr, e),
+            }
+        });
+    }
+
+    anyhow::bail!("DoQ 监听器 '{}' 已关闭", name)
+}
+
+/// 处理 DoQ 连接：每个双向流承载一个查询
+async fn handle_doq_connection(
+    connection: quinn::Connection,
+    forwarder: Arc<DnsForwarder>,
+    listener_name: String,
+) {
+    let peer_addr = connection.remote_address();
+
+    loop {
+        let (send, recv) = match connection.accept_bi().await {
+            Ok(streams) => streams,
+            Err(e) => {
+                debug!("DoQ 连接结束 [{}]: {}", peer_addr, e);
+                return;
+            }
+        };
+
+        let connection = connection.clone();
+        let forwarder = Arc::clone(&forwarder);
+        let listener_name = listener_name.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_doq_stream(send, recv, &connection, forwarder, &listener_name).await {
+                error!("DoQ 查询处理失败 [{}]: {}", peer_addr, e);
+            }
+        });
+    }
+}
+
+/// 处理单个 DoQ 流（2 字节长度前缀 + DNS 消息，客户端发送后关闭写端）
+async fn handle_doq_stream(
+    mut send: quinn::SendStream,
+    mut recv: quinn::RecvStream,
+    connection: &quinn::Connection,
+    forwarder: Arc<DnsForwarder>,
+    listener_name: &str,
+) -> Result<()> {
+    let data = recv.read_to_end(2 + MAX_DNS_MESSAGE_LEN).await?;
+
+    let request = match data.split_first_chunk::<2>() {
+        Some((len_buf, payload)) if u16::from_be_bytes(*len_buf) as usize == payload.len() => {
+            Message::from_vec(payload).ok()
+        }
+        _ => None,
+    };
+
+    // RFC 9250 §4.2.1：消息 ID 必须为 0，否则视为协议错误
+    let request = match request {
+        Some(request) if request.id() == 0 => request,
+        _ => {
+            connection.close(DOQ_PROTOCOL_ERROR.into(), b"invalid DoQ query");
+            anyhow::bail!("无效的 DoQ 查询，已关闭连接");
+        }
+    };
+
+    debug!("收到来自 {} 的 DoQ DNS 查询 (监听器: {})", connection.remote_address(), listener_name);
+    for query in request.queries() {
+        debug!("  查询: {} ({})", query.name(), query.query_type());
+    }
+
+    let response = answer_query(&forwarder, &request, listener_name, connection.remote_address()).await
+        .ok_or_else(|| anyhow::anyhow!("DoQ 收到非查询报文"))?;
+    let response_data = response.to_vec()?;
+
+    let mut framed = Vec::with_capacity(2 + response_data.len());
+    framed.extend_from_slice(&(response_data.len() as u16).to_be_bytes());
+    framed.extend_from_slice(&response_data);
+    send.write_all(&framed).await?;
+    send.finish().await?;
+
+    debug!("DoQ 响应已发送至 {}", connection.remote_address());
+    Ok(())
+}
+
+/// 处理 TCP 连接（同时用于 DoT）
+///
+/// 按 RFC 7766 保持连接并支持流水线查询：同一连接上的多个查询并发处理，
+/// 响应按完成顺序写回，空闲超过 `TCP_IDLE_TIMEOUT` 后关闭连接；
+/// 同时处理的查询达到 `TCP_MAX_PENDING_QUERIES` 时暂停读取，直到有响应写回
+async fn handle_tcp_connection<S>(
+    socket: S,
+    forwarder: Arc<DnsForwarder>,
+    peer_addr: SocketAddr,
+    listener_name: String,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (mut reader, mut writer) = tokio::io::split(socket);
+    let (tx, mut rx) = mpsc::channel::<(Vec<u8>, OwnedSemaphorePermit)>(TCP_MAX_PENDING_QUERIES);
+    // 每个查询持有一个许可，响应写回后释放
+    let pending = Arc::new(Semaphore::new(TCP_MAX_PENDING_QUERIES));
+
+    // 读取循环：持续读取长度前缀消息，每个查询独立处理，允许多个查询同时进行
+    let read_loop = async move {
+        loop {
+            let mut len_buf = [0u8; 2];
+            match tokio::time::timeout(TCP_IDLE_TIMEOUT, reader.read_exact(&mut len_buf)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => {
+                    // 仍有查询未完成时不关闭连接（除读取循环外每个查询持有一个发送端）
+                    if tx.strong_count() > 1 {
+                        continue;
+                    }
+                    debug!("TCP 连接 {} 空闲超时，关闭连接", peer_addr);
+                    break;
+                }
+            }
+
+            let msg_len = u16::from_be_bytes(len_buf) as usize;
+            if msg_len == 0 {
+                anyhow::bail!("无效的消息长度: {}", msg_len);
+            }
+
+            // 读取 DNS 消息（长度前缀之后对端停止发送时同样按空闲超时关闭）
+            let mut buf = vec![0u8; msg_len];
+            match tokio::time::timeout(TCP_IDLE_TIMEOUT, reader.read_exact(&mut buf)).await {
+                Ok(result) => result?,
+                Err(_) => anyhow::bail!("TCP 连接 {} 读取消息超时", peer_addr),
+            };
+            let permit = Arc::clone(&pending).acquire_owned().await?;
+
+            // 解析 DNS 请求，无法解析时回复 FORMERR 并继续处理后续查询
+            let request = match Message::from_vec(&buf) {
+                Ok(request) => request,
+                Err(e) => {
+                    debug!("TCP 查询解析失败 [{}]: {}", peer_addr, e);
+                    if let Some(response) = dns::formerr_response(&buf) {
+                        let _ = tx.send((response.to_vec()?, permit)).await;
+                    }
+                    continue;
+                }
+            };
+
+            debug!("收到来自 {} 的 TCP/DoT DNS 查询 (监听器: {})", peer_addr, listener_name);
+            for query in request.queries() {
+                debug!("  查询: {} ({})", query.name(), query.query_type());
+            }
+
+            let forwarder = Arc::clone(&forwarder);
+            let listener_name = listener_name.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let Some(response) = answer_query(&forwarder, &request, &listener_name, peer_addr).await else {
+                    return;
+                };
+                match response.to_vec() {
+                    Ok(response_data) => {
+                        let _ = tx.send((response_data, permit)).await;
+                    }
+                    Err(e) => error!("TCP 响应编码失败 [{}]: {}", peer_addr, e),
+                }
+            });
+        }
+        anyhow::Ok(())
+    };
+
+    // 写入循环：按完成顺序写回响应（可乱序），所有发送端释放后结束
+    let write_loop = async move {
+        while let Some((response_data, _permit)) = rx.recv().await {
+            let mut frame = Vec::with_capacity(response_data.len() + 2);
+            frame.extend_from_slice(&(response_data.len() as u16).to_be_bytes());
+            frame.extend_from_slice(&response_data);
+            writer.write_all(&frame).await?;
+            debug!("TCP 响应已发送至 {}", peer_addr);
+        }
+        writer.shutdown().await?;
+        anyhow::Ok(())
+    };
+
+    tokio::try_join!(read_loop, write_loop)?;
+    Ok(())
+}
+
+async fn handle_udp_query(
+    socket: Arc<UdpSocket>,
+    forwarder: Arc<DnsForwarder>,
+    peer_addr: SocketAddr,
+    data: Vec<u8>,
+    listener_name: String,
+) -> Result<()> {
+    // 解析 DNS 请求，无法解析时回复 FORMERR
+    let request = match Message::from_vec(&data) {
+        Ok(request) => request,
+        Err(e) => {
+            debug!("UDP 查询解析失败 [{}]: {}", peer_addr, e);
+            if let Some(response) = dns::formerr_response(&data) {
+                socket.send_to(&response.to_vec()?, peer_addr).await?;
+            }
+            return Ok(());
+        }
+    };
+
+    debug!("收到来自 {} 的 UDP DNS 查询 (监听器: {})", peer_addr, listener_name);
+    for query in request.queries() {
+        debug!("  查询: {} ({})", query.name(), query.query_type());
+    }
+
+    // 转发查询
+    let Some(response) = answer_query(&forwarder, &request, &listener_name, peer_addr).await else {
+        return Ok(());
+    };
+
+    // 按客户端通告的 EDNS0 缓冲区大小（无 EDNS0 时为 512）截断响应
+    let max_size = request.max_payload().min(MAX_UDP_RESPONSE_SIZE) as usize;
+    let mut response_data = response.to_vec()?;
+    if response_data.len() > max_size {
+        debug!("UDP 响应 {} 字节超过客户端缓冲区 {} 字节，设置 TC 标志", response_data.len(), max_size);
+        response_data = truncate_response(&response).to_vec()?;
+    }
+
+    // 返回响应
+    socket.send_to(&response_data, peer_addr).await?;
+
+    debug!("UDP 响应已发送至 {}", peer_addr);
+    Ok(())
+}
+
+/// 处理已解析的查询，任何失败都合成带对应 RCODE 的响应，保证客户端总能收到应答
+///
+/// - 非标准查询操作码返回 NOTIMP
+/// - 问题数不为 1 返回 FORMERR
+/// - 策略拒绝返回 REFUSED，其余转发失败返回 SERVFAIL
+///
+/// 收到的是响应报文时返回 `None`，不予回复
+async fn answer_query(
+    forwarder: &Arc<DnsForwarder>,
+    request: &Message,
+    listener_name: &str,
+    peer_addr: SocketAddr,
+) -> Option<Message> {
+    if request.message_type() != MessageType::Query {
+        debug!("忽略来自 {} 的非查询报文", peer_addr);
+        return None;
+    }
+    if request.op_code() != OpCode::Query {
+        return Some(dns::error_response(request, ResponseCode::NotImp, Some(ExtendedError::NotSupported)));
+    }
+    if request.queries().len() != 1 {
+        return Some(dns::error_response(request, ResponseCode::FormErr, Some(ExtendedError::Other)));
+    }
+
+    match forwarder.forward_with_listener(request, listener_name).await {
+        Ok(response) => Some(response),
+        Err(e) if e.downcast_ref::<QueryRefused>().is_some() => {
+            warn!("监听器 '{}' 拒绝查询 [{}]: {}", listener_name, peer_addr, e);
+            Some(dns::error_response(request, ResponseCode::Refused, Some(ExtendedError::Prohibited)))
+        }
+        Err(e) => {
+            error!("监听器 '{}' 处理查询失败 [{}]: {}", listener_name, peer_addr, e);
+            Some(dns::error_response(request, ResponseCode::ServFail, Some(ExtendedError::NoReachableAuthority)))
+        }
+    }
+}
+
+/// 生成截断响应：保留头部、问题与 EDNS0，清空各记录段并设置 TC 标志，客户端将改用 TCP 重试
+fn truncate_response(response: &Message) -> Message {
+    let mut truncated = response.clone();
+    truncated.set_truncated(true);
+    truncated.take_answers();
+    truncated.take_name_servers();
+    truncated.take_additionals();
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::{Edns, Query};
+    use hickory_proto::rr::{Name, RData, Record, RecordType};
+    use hickory_proto::rr::rdata::TXT;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_truncate_response() {
+        let name = Name::from_str("example.com.").unwrap();
+        let mut response = Message::new();
+        response.set_id(0x1234);
+        response.add_query(Query::query(name.clone(), RecordType::TXT));
+        response.set_edns(Edns::new());
+        for _ in 0..20 {
+            let txt = TXT::new(vec!["x".repeat(200)]);
+            response.add_answer(Record::from_rdata(name.clone(), 300, RData::TXT(txt)));
+        }
+        assert!(response.to_vec().unwrap().len() > 512);
+
+        let truncated = truncate_response(&response);
+        let data = truncated.to_vec().unwrap();
+        assert!(data.len() <= 512);
+
+        let parsed = Message::from_vec(&data).unwrap();
+        assert!(parsed.truncated());
+        assert_eq!(parsed.id(), 0x1234);
+        assert_eq!(parsed.queries().len(), 1);
+        assert!(parsed.answers().is_empty());
+        assert!(parsed.extensions().is_some());
+    }
+}
@@ -1,10 +1,7 @@
 use anyhow::Result;
-use hickory_proto::op::Message;
-use std::net::SocketAddr;
 use std::sync::{Arc, Mutex, RwLock};
 use std::env;
 use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::net::UdpSocket as TokioUdpSocket;
 use tokio::time::{sleep, Duration};
 use tracing::{debug, error, info};
 use std::collections::HashMap;
@@ -12,12 +9,22 @@ use std::collections::HashMap;
 mod config;
 mod forwarder;
 mod dns;
+mod doh;
+mod doq;
+mod bootstrap;
 mod cache;
+mod eviction;
 mod log;
+mod listener;
+mod pool;
+mod proxy;
+mod singleflight;
+mod tls;
+mod upstream;
 
 use config::{Config, DomainListReloadState};
 use forwarder::DnsForwarder;
-use cache::{CacheManager};
+use cache::{CacheKey, CacheManager};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -87,8 +94,8 @@ async fn main() -> Result<()> {
     }
 
     // 显示所有监听器
-    for (name, port) in &config.listener {
-        info!("监听器 '{}' 端口: {}", name, port);
+    for (name, listener) in &config.listener {
+        info!("监听器 '{}' 类型: {:?}, 端口: {}, 绑定地址: {:?}", name, listener.r#type, listener.port, listener.bind);
     }
 
     // 创建共享的域名列表管理器（用于热重新加载）
@@ -141,12 +148,26 @@ async fn main() -> Result<()> {
     let warm_up_list = cache_manager.cold_start(&config).await?;
     
     // 创建转发器（在冷启动之后）
-    let forwarder = Arc::new(DnsForwarder::new(
+    let forwarder = DnsForwarder::new(
         config.clone(),
         cache_manager.get_rule_cache(),
         cache_manager.get_domain_cache("domain"), // 使用 "domain" 缓存作为默认
-    )?);
+    )?;
     
+    // 启动上游健康检查
+    if config.health_check.enabled {
+        let probe_query = config.health_check.probe_query()?;
+        let interval_secs = config.health_check.interval_secs()?;
+        tokio::spawn(Arc::clone(&forwarder).run_health_checks(probe_query, interval_secs));
+    }
+
+    // 启动热点记录预取（通过记录原来的上游重新查询）
+    let prefetch_forwarder = Arc::clone(&forwarder);
+    cache_manager.spawn_prefetch(move |upstream, request| {
+        let forwarder = Arc::clone(&prefetch_forwarder);
+        async move { forwarder.query_upstream(&upstream, &request).await }
+    });
+
     // 执行预热查询（如果有需要预热的域名）
     if !warm_up_list.is_empty() {
         info!("开始预热查询: {} 个域名", warm_up_list.len());
@@ -182,6 +203,7 @@ async fn main() -> Result<()> {
                 
                 // 清理过期缓存
                 cache_manager_clone.cleanup_all_expired();
+                debug!("缓存统计:\n{}", cache_manager_clone.stats_all().trim_end());
                 
                 // 导出缓存
                 if let Err(e) = cache_manager_clone.export_all() {
@@ -205,10 +227,10 @@ async fn main() -> Result<()> {
     // 为每个监听器启动处理任务
     let mut handles = vec![];
 
-    for (name, port) in config.listener {
+    for (name, listener_config) in config.listener {
         let forwarder = Arc::clone(&forwarder);
         let handle = tokio::spawn(async move {
-            if let Err(e) = run_listener(name, port, forwarder).await {
+            if let Err(e) = listener::run_listener(name, listener_config, forwarder).await {
                 error!("监听器错误: {}", e);
             }
         });
@@ -327,189 +349,6 @@ async fn monitor_domain_list_reload(
     }
 }
 
-/// 运行单个监听器
-async fn run_listener(
-    name: String,
-    port: u16,
-    forwarder: Arc<DnsForwarder>,
-) -> Result<()> {
-    let addr = format!("127.0.0.1:{}", port);
-    info!("监听器 '{}' 启动在 {} (UDP/TCP)", name, addr);
-
-    // 启动 UDP 监听器
-    let udp_forwarder = Arc::clone(&forwarder);
-    let udp_name = format!("{}-udp", &name);
-    let name_for_udp = name.clone();
-    let udp_handle = tokio::spawn(async move {
-        if let Err(e) = run_udp_listener(udp_name, port, udp_forwarder, name_for_udp).await {
-            error!("UDP 监听器错误: {}", e);
-        }
-    });
-
-    // 启动 TCP 监听器
-    let tcp_forwarder = Arc::clone(&forwarder);
-    let tcp_name = format!("{}-tcp", &name);
-    let tcp_handle = tokio::spawn(async move {
-        if let Err(e) = run_tcp_listener(tcp_name, port, tcp_forwarder, name).await {
-            error!("TCP 监听器错误: {}", e);
-        }
-    });
-
-    // 等待两个监听器
-    let (udp_result, tcp_result) = tokio::join!(udp_handle, tcp_handle);
-    
-    if let Err(e) = udp_result {
-        error!("UDP 监听器任务失败: {}", e);
-    }
-    if let Err(e) = tcp_result {
-        error!("TCP 监听器任务失败: {}", e);
-    }
-
-    Ok(())
-}
-
-/// 运行 UDP 监听器
-async fn run_udp_listener(
-    name: String,
-    port: u16,
-    forwarder: Arc<DnsForwarder>,
-    listener_name: String,
-) -> Result<()> {
-    let listen_addr: SocketAddr = format!("127.0.0.1:{}", port).parse()?;
-    let socket = TokioUdpSocket::bind(listen_addr).await?;
-    let socket = Arc::new(socket);
-
-    debug!("UDP 监听器 '{}' 绑定到 {}", name, listen_addr);
-
-    let log_name = name.clone();
-    // 处理 DNS 查询
-    loop {
-        let mut buf = [0u8; 512];
-        match socket.recv_from(&mut buf).await {
-            Ok((len, peer_addr)) => {
-                let socket = Arc::clone(&socket);
-                let forwarder = Arc::clone(&forwarder);
-                let data = buf[..len].to_vec();
-                let listener_name = listener_name.clone();
-                let log_name = log_name.clone();
-
-                tokio::spawn(async move {
-                    if let Err(e) = handle_udp_query(socket, forwarder, peer_addr, data, listener_name).await {
-                        error!("UDP 监听器 '{}' 处理查询失败 [{}]: {}", log_name, peer_addr, e);
-                    }
-                });
-            }
-            Err(e) => {
-                error!("UDP 监听器 '{}' 接收数据失败: {}", name, e);
-            }
-        }
-    }
-}
-
-/// 运行 TCP 监听器
-async fn run_tcp_listener(
-    name: String,
-    port: u16,
-    forwarder: Arc<DnsForwarder>,
-    listener_name: String,
-) -> Result<()> {
-    use tokio::net::TcpListener;
-
-    let listen_addr = format!("127.0.0.1:{}", port);
-    let listener = TcpListener::bind(&listen_addr).await?;
-
-    debug!("TCP 监听器 '{}' 绑定到 {}", name, listen_addr);
-
-    loop {
-        match listener.accept().await {
-            Ok((socket, peer_addr)) => {
-                let forwarder = Arc::clone(&forwarder);
-                let listener_name = listener_name.clone();
-                
-                tokio::spawn(async move {
-                    if let Err(e) = handle_tcp_connection(socket, forwarder, peer_addr, listener_name).await {
-                        error!("TCP 连接处理失败 [{}]: {}", peer_addr, e);
-                    }
-                });
-            }
-            Err(e) => {
-                error!("TCP 监听器 '{}' 接受连接失败: {}", name, e);
-            }
-        }
-    }
-}
-
-/// 处理 TCP 连接
-async fn handle_tcp_connection(
-    mut socket: tokio::net::TcpStream,
-    forwarder: Arc<DnsForwarder>,
-    peer_addr: SocketAddr,
-    listener_name: String,
-) -> Result<()> {
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
-
-    // 读取长度前缀 (2 字节)
-    let mut len_buf = [0u8; 2];
-    socket.read_exact(&mut len_buf).await?;
-    let msg_len = u16::from_be_bytes(len_buf) as usize;
-
-    if msg_len == 0 || msg_len > 4096 {
-        anyhow::bail!("无效的消息长度: {}", msg_len);
-    }
-
-    // 读取 DNS 消息
-    let mut buf = vec![0u8; msg_len];
-    socket.read_exact(&mut buf).await?;
-
-    // 解析 DNS 请求
-    let request = Message::from_vec(&buf)?;
-    
-    debug!("收到来自 {} 的 TCP DNS 查询 (监听器: {})", peer_addr, listener_name);
-    for query in request.queries() {
-        debug!("  查询: {} ({})", query.name(), query.query_type());
-    }
-
-    // 转发查询
-    let response = forwarder.forward_with_listener(&request, &listener_name).await?;
-    let response_data = response.to_vec()?;
-
-    // 发送长度前缀
-    let response_len = (response_data.len() as u16).to_be_bytes();
-    socket.write_all(&response_len).await?;
-    
-    // 发送响应数据
-    socket.write_all(&response_data).await?;
-
-    debug!("TCP 响应已发送至 {}", peer_addr);
-    Ok(())
-}
-
-async fn handle_udp_query(
-    socket: Arc<TokioUdpSocket>,
-    forwarder: Arc<DnsForwarder>,
-    peer_addr: SocketAddr,
-    data: Vec<u8>,
-    listener_name: String,
-) -> Result<()> {
-    // 解析 DNS 请求
-    let request = Message::from_vec(&data)?;
-    
-    debug!("收到来自 {} 的 UDP DNS 查询 (监听器: {})", peer_addr, listener_name);
-    for query in request.queries() {
-        debug!("  查询: {} ({})", query.name(), query.query_type());
-    }
-
-    // 转发查询
-    let response = forwarder.forward_with_listener(&request, &listener_name).await?;
-
-    // 返回响应
-    let response_data = response.to_vec()?;
-    socket.send_to(&response_data, peer_addr).await?;
-
-    debug!("UDP 响应已发送至 {}", peer_addr);
-    Ok(())
-}
-
 /// 解析命令行参数
 fn parse_args() -> (Option<String>, Option<String>) {
     let args: Vec<String> = env::args().collect();
@@ -596,12 +435,9 @@ fn print_help() {
 /// 预热查询：对冷启动加载的域名进行实际 DNS 查询
 async fn warm_up_queries(
     forwarder: Arc<DnsForwarder>,
-    warm_up_list: Vec<(String, String, String, String)>,
+    warm_up_list: Vec<(CacheKey, String, String, String)>,
     cold_start_config: &config::ColdStartConfig,
 ) {
-    use hickory_proto::op::{Message, Query, OpCode};
-    use hickory_proto::rr::{Name, RecordType};
-    use std::str::FromStr;
     use futures::stream::{self, StreamExt};
     
     let total = warm_up_list.len();
@@ -615,25 +451,18 @@ async fn warm_up_queries(
     
     // 使用并发流处理
     let results = stream::iter(warm_up_list)
-        .map(|(qname, _match_domain, _upstream, _cache_id)| {
+        .map(|(key, _match_domain, _upstream, _cache_id)| {
             let forwarder = Arc::clone(&forwarder);
-            let qname = qname.clone();
             async move {
-                // 构造 DNS 查询
-                let domain_name = match Name::from_str(&format!("{}.", &qname)) {
-                    Ok(name) => name,
+                // 按缓存键构造 DNS 查询（类型、类别与 DO/CD 位与原查询一致）
+                let request = match key.to_request() {
+                    Ok(request) => request,
                     Err(e) => {
-                        error!("预热查询: 域名格式错误 '{}': {}", qname, e);
+                        error!("预热查询: 域名格式错误 '{}': {}", key.qname, e);
                         return Err(());
                     }
                 };
                 
-                let mut request = Message::new();
-                request.set_id(rand::random());
-                request.set_op_code(OpCode::Query);
-                request.set_recursion_desired(true);
-                request.add_query(Query::query(domain_name, RecordType::A));
-                
                 // 执行查询（带超时）
                 let query_result = tokio::time::timeout(
                     Duration::from_millis(timeout_ms),
@@ -642,15 +471,15 @@ async fn warm_up_queries(
                 
                 match query_result {
                     Ok(Ok(_response)) => {
-                        debug!("预热查询成功: {}", qname);
+                        debug!("预热查询成功: {}", key);
                         Ok(())
                     }
                     Ok(Err(e)) => {
-                        debug!("预热查询失败: {} - {}", qname, e);
+                        debug!("预热查询失败: {} - {}", key, e);
                         Err(())
                     }
                     Err(_) => {
-                        debug!("预热查询超时: {}", qname);
+                        debug!("预热查询超时: {}", key);
                         Err(())
                     }
                 }
@@ -0,0 +1,539 @@
+use anyhow::Result;
+use hickory_proto::op::Message;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+use crate::proxy::{UdpAssociation, MAX_SOCKS5_UDP_HEADER_LEN};
+
+/// DNS 消息最大长度
+const MAX_DNS_MESSAGE_LEN: usize = 65535;
+
+/// 单个上游的连接池，连接上按消息 ID 复用（流水线）
+///
+/// 用于 DoT 等长度前缀格式的流式连接。连接在对端关闭或读写出错后失效，
+/// 下次取用时自动清理
+pub struct ConnectionPool {
+    /// 上游地址（用于日志）
+    name: String,
+    /// 最大连接数
+    size: usize,
+    connections: Mutex<Vec<Arc<PipelinedConnection>>>,
+    /// 同一时间只建立一个新连接，避免突发查询同时握手
+    connect_lock: tokio::sync::Mutex<()>,
+}
+
+impl ConnectionPool {
+    pub fn new(name: &str, size: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            size: size.max(1),
+            connections: Mutex::new(Vec::new()),
+            connect_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// 获取连接：优先复用空闲连接，需要时通过 `connect` 建立新连接
+    ///
+    /// 返回的布尔值表示是否为复用的已有连接
+    pub async fn get<F, Fut, S>(&self, connect: F) -> Result<(Arc<PipelinedConnection>, bool)>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<S>>,
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        if let Some(connection) = self.acquire() {
+            return Ok((connection, true));
+        }
+
+        let _guard = self.connect_lock.lock().await;
+        // 等待期间其他查询可能已建立连接
+        if let Some(connection) = self.acquire() {
+            return Ok((connection, true));
+        }
+        Ok((self.add(connect().await?), false))
+    }
+
+    /// 取出负载最低的可用连接
+    ///
+    /// 没有可用连接，或所有连接都有进行中的查询且连接数未达上限时返回 None
+    fn acquire(&self) -> Option<Arc<PipelinedConnection>> {
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|connection| !connection.is_closed());
+
+        let connection = connections.iter().min_by_key(|connection| connection.in_flight())?;
+        if connection.in_flight() > 0 && connections.len() < self.size {
+            return None;
+        }
+        Some(Arc::clone(connection))
+    }
+
+    /// 将新建立的连接放入连接池（连接池已满时仅供本次查询使用）
+    fn add<S>(&self, stream: S) -> Arc<PipelinedConnection>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let connection = Arc::new(PipelinedConnection::new(stream, &self.name));
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|connection| !connection.is_closed());
+        if connections.len() < self.size {
+            connections.push(Arc::clone(&connection));
+        }
+        debug!("上游 {} 新建连接，连接池: {}/{}", self.name, connections.len(), self.size);
+        connection
+    }
+}
+
+/// 等待响应的查询（连接内的消息 ID -> 响应通道）
+type Pending = Mutex<HashMap<u16, oneshot::Sender<Message>>>;
+
+/// 支持流水线查询的长度前缀连接
+///
+/// 每个查询分配连接内唯一的消息 ID，后台读取任务按 ID 将响应分发给对应的查询
+pub struct PipelinedConnection {
+    writer: tokio::sync::Mutex<Pin<Box<dyn AsyncWrite + Send>>>,
+    pending: Arc<Pending>,
+    closed: Arc<AtomicBool>,
+    reader: JoinHandle<()>,
+}
+
+impl PipelinedConnection {
+    fn new<S>(stream: S, name: &str) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, writer) = tokio::io::split(stream);
+        let pending: Arc<Pending> = Arc::new(Mutex::new(HashMap::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let reader = tokio::spawn(Self::read_loop(
+            reader,
+            Arc::clone(&pending),
+            Arc::clone(&closed),
+            name.to_string(),
+        ));
+
+        Self {
+            writer: tokio::sync::Mutex::new(Box::pin(writer)),
+            pending,
+            closed,
+            reader,
+        }
+    }
+
+    /// 后台读取响应并按消息 ID 分发；连接结束时丢弃所有等待中的查询
+    async fn read_loop<R>(mut reader: R, pending: Arc<Pending>, closed: Arc<AtomicBool>, name: String)
+    where
+        R: AsyncRead + Unpin,
+    {
+        let result: Result<()> = async {
+            loop {
+                let mut len_buf = [0u8; 2];
+                reader.read_exact(&mut len_buf).await?;
+                let mut buf = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+                reader.read_exact(&mut buf).await?;
+
+                let response = Message::from_vec(&buf)?;
+                match pending.lock().unwrap().remove(&response.id()) {
+                    Some(sender) => {
+                        let _ = sender.send(response);
+                    }
+                    None => debug!("上游 {} 返回了未知消息 ID {} 的响应", name, response.id()),
+                }
+            }
+        }
+        .await;
+
+        if let Err(e) = result {
+            debug!("上游 {} 连接已关闭: {}", name, e);
+        }
+        closed.store(true, Ordering::Release);
+        pending.lock().unwrap().clear();
+    }
+
+    /// 连接是否已失效
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// 进行中的查询数
+    fn in_flight(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// 关闭连接，所有等待中的查询立即失败
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.reader.abort();
+        self.pending.lock().unwrap().clear();
+    }
+
+    /// 在连接上发送查询并等待响应（响应的消息 ID 恢复为原请求 ID）
+    ///
+    /// 写入出错或写入中途超时（帧可能不完整）时关闭连接；等待响应超时只放弃本查询，
+    /// 连接上的其他查询不受影响
+    pub async fn query(&self, request: &Message, timeout: Duration) -> Result<Message> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let (id, receiver) = self.register()?;
+
+        let mut message = request.clone();
+        message.set_id(id);
+        let written = tokio::time::timeout_at(deadline, async {
+            let data = message.to_vec()?;
+            let mut frame = Vec::with_capacity(data.len() + 2);
+            frame.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            frame.extend_from_slice(&data);
+
+            let mut writer = self.writer.lock().await;
+            writer.write_all(&frame).await?;
+            writer.flush().await?;
+            Ok::<_, anyhow::Error>(())
+        })
+        .await;
+        match written {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                self.close();
+                return Err(e);
+            }
+            Err(elapsed) => {
+                self.close();
+                return Err(anyhow::Error::new(elapsed).context(format!("查询超时 ({}s)", timeout.as_secs())));
+            }
+        }
+
+        match tokio::time::timeout_at(deadline, receiver).await {
+            Ok(Ok(mut response)) => {
+                // 超时查询的迟到响应可能命中复用的消息 ID，按问题部分校验
+                if response.queries() != message.queries() {
+                    anyhow::bail!("响应的问题部分与查询不符");
+                }
+                response.set_id(request.id());
+                Ok(response)
+            }
+            // 读取任务结束时已标记连接失效
+            Ok(Err(_)) => Err(anyhow::anyhow!("连接已关闭")),
+            Err(elapsed) => {
+                self.pending.lock().unwrap().remove(&id);
+                Err(anyhow::Error::new(elapsed).context(format!("查询超时 ({}s)", timeout.as_secs())))
+            }
+        }
+    }
+
+    /// 分配连接内未使用的消息 ID 并登记响应通道
+    fn register(&self) -> Result<(u16, oneshot::Receiver<Message>)> {
+        register(&self.pending, &self.closed)
+    }
+}
+
+impl Drop for PipelinedConnection {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// 分配未使用的消息 ID 并登记响应通道（连接已失效时返回错误）
+fn register(pending: &Pending, closed: &AtomicBool) -> Result<(u16, oneshot::Receiver<Message>)> {
+    let mut pending = pending.lock().unwrap();
+    if closed.load(Ordering::Acquire) {
+        anyhow::bail!("连接已关闭");
+    }
+    if pending.len() > u16::MAX as usize / 2 {
+        anyhow::bail!("连接上进行中的查询过多");
+    }
+
+    let id = loop {
+        let id = rand::random::<u16>();
+        if !pending.contains_key(&id) {
+            break id;
+        }
+    };
+    let (sender, receiver) = oneshot::channel();
+    pending.insert(id, sender);
+    Ok((id, receiver))
+}
+
+/// 经 SOCKS5 UDP 关联发往单个上游的查询会话，按消息 ID 分发响应
+///
+/// 关联（含控制连接与中继端口）在多个查询间复用；控制连接关闭或收发出错后失效，
+/// 由调用方重新建立
+pub struct RelayedUdpSession {
+    association: Arc<UdpAssociation>,
+    /// 上游地址（只接受来自该地址的响应）
+    target: SocketAddr,
+    pending: Arc<Pending>,
+    closed: Arc<AtomicBool>,
+    reader: JoinHandle<()>,
+}
+
+impl RelayedUdpSession {
+    pub fn new(association: UdpAssociation, target: SocketAddr, name: &str) -> Self {
+        let association = Arc::new(association);
+        let pending: Arc<Pending> = Arc::new(Mutex::new(HashMap::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let reader = tokio::spawn(Self::read_loop(
+            Arc::clone(&association),
+            target,
+            Arc::clone(&pending),
+            Arc::clone(&closed),
+            name.to_string(),
+        ));
+
+        Self { association, target, pending, closed, reader }
+    }
+
+    /// 后台接收响应并按消息 ID 分发（接收缓冲区只分配一次）；关联失效时丢弃所有等待中的查询
+    async fn read_loop(
+        association: Arc<UdpAssociation>,
+        target: SocketAddr,
+        pending: Arc<Pending>,
+        closed: Arc<AtomicBool>,
+        name: String,
+    ) {
+        let mut packet = vec![0u8; MAX_DNS_MESSAGE_LEN + MAX_SOCKS5_UDP_HEADER_LEN];
+        let result: Result<()> = async {
+            loop {
+                let (source, data) = tokio::select! {
+                    received = association.recv_from(&mut packet) => received?,
+                    _ = association.closed() => anyhow::bail!("代理关闭了控制连接"),
+                };
+                if source != target {
+                    debug!("上游 {} 的 UDP 关联收到来自 {} 的数据报，已丢弃", name, source);
+                    continue;
+                }
+                let response = match Message::from_vec(data) {
+                    Ok(response) => response,
+                    Err(e) => {
+                        debug!("上游 {} 返回了无效的响应: {}", name, e);
+                        continue;
+                    }
+                };
+                match pending.lock().unwrap().remove(&response.id()) {
+                    Some(sender) => {
+                        let _ = sender.send(response);
+                    }
+                    None => debug!("上游 {} 返回了未知消息 ID {} 的响应", name, response.id()),
+                }
+            }
+        }
+        .await;
+
+        if let Err(e) = result {
+            debug!("上游 {} 的 UDP 关联已失效: {}", name, e);
+        }
+        closed.store(true, Ordering::Release);
+        pending.lock().unwrap().clear();
+    }
+
+    /// 会话是否已失效
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// 关闭会话，所有等待中的查询立即失败
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.reader.abort();
+        self.pending.lock().unwrap().clear();
+    }
+
+    /// 发送查询并等待响应（响应的消息 ID 恢复为原请求 ID）
+    ///
+    /// 发送出错时关闭会话；等待响应超时只放弃本查询
+    pub async fn query(&self, request: &Message, timeout: Duration) -> Result<Message> {
+        let (id, receiver) = register(&self.pending, &self.closed)?;
+
+        let mut message = request.clone();
+        message.set_id(id);
+        let data = message.to_vec()?;
+        if let Err(e) = self.association.send_to(&data, self.target).await {
+            self.close();
+            return Err(e);
+        }
+
+        match tokio::time::timeout(timeout, receiver).await {
+            Ok(Ok(mut response)) => {
+                // 超时查询的迟到响应可能命中复用的消息 ID，按问题部分校验
+                if response.queries() != message.queries() {
+                    anyhow::bail!("响应的问题部分与查询不符");
+                }
+                response.set_id(request.id());
+                Ok(response)
+            }
+            Ok(Err(_)) => Err(anyhow::anyhow!("UDP 关联已失效")),
+            Err(elapsed) => {
+                self.pending.lock().unwrap().remove(&id);
+                Err(anyhow::Error::new(elapsed).context(format!("查询超时 ({}s)", timeout.as_secs())))
+            }
+        }
+    }
+}
+
+impl Drop for RelayedUdpSession {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::Query;
+    use hickory_proto::rr::{Name, RecordType};
+    use std::str::FromStr;
+
+    /// 模拟上游：读取两个查询后按相反顺序返回响应
+    async fn reversed_upstream(mut stream: tokio::io::DuplexStream) {
+        let mut requests = Vec::new();
+        for _ in 0..2 {
+            let mut len_buf = [0u8; 2];
+            stream.read_exact(&mut len_buf).await.unwrap();
+            let mut buf = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+            stream.read_exact(&mut buf).await.unwrap();
+            requests.push(Message::from_vec(&buf).unwrap());
+        }
+        for request in requests.into_iter().rev() {
+            let mut response = request.clone();
+            response.set_message_type(hickory_proto::op::MessageType::Response);
+            let data = response.to_vec().unwrap();
+            stream.write_all(&(data.len() as u16).to_be_bytes()).await.unwrap();
+            stream.write_all(&data).await.unwrap();
+        }
+    }
+
+    fn request(id: u16, name: &str) -> Message {
+        let mut request = Message::new();
+        request.set_id(id);
+        request.add_query(Query::query(Name::from_str(name).unwrap(), RecordType::A));
+        request
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_out_of_order_responses() {
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(reversed_upstream(server));
+
+        let pool = ConnectionPool::new("test", 2);
+        let (connection, reused) = pool.get(|| async { Ok(client) }).await.unwrap();
+        assert!(!reused);
+
+        let timeout = Duration::from_secs(5);
+        let first = request(7, "a.example.");
+        let second = request(7, "b.example.");
+        let (a, b) = tokio::join!(connection.query(&first, timeout), connection.query(&second, timeout));
+
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert_eq!(a.id(), 7);
+        assert_eq!(a.queries()[0].name(), first.queries()[0].name());
+        assert_eq!(b.queries()[0].name(), second.queries()[0].name());
+
+        // 上游关闭连接后连接失效并从连接池移除
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(connection.is_closed());
+        assert!(pool.acquire().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_timeout_keeps_connection() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        // 模拟上游：不应答第一个查询，只应答第二个
+        tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for _ in 0..2 {
+                let mut len_buf = [0u8; 2];
+                server.read_exact(&mut len_buf).await.unwrap();
+                let mut buf = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+                server.read_exact(&mut buf).await.unwrap();
+                requests.push(Message::from_vec(&buf).unwrap());
+            }
+            let data = requests[1].to_vec().unwrap();
+            server.write_all(&(data.len() as u16).to_be_bytes()).await.unwrap();
+            server.write_all(&data).await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let connection = PipelinedConnection::new(client, "test");
+        let slow = request(1, "slow.example.");
+        let fast = request(2, "fast.example.");
+        let (slow, fast) = tokio::join!(
+            connection.query(&slow, Duration::from_millis(100)),
+            connection.query(&fast, Duration::from_secs(5)),
+        );
+
+        // 一个查询超时不影响同一连接上的其他查询，连接仍可用
+        assert!(slow.is_err());
+        assert_eq!(fast.unwrap().id(), 2);
+        assert!(!connection.is_closed());
+        assert_eq!(connection.in_flight(), 0);
+    }
+
+    /// SOCKS5 UDP 数据报（IPv4 地址头）
+    fn socks5_datagram(source: SocketAddr, data: &[u8]) -> Vec<u8> {
+        let SocketAddr::V4(source) = source else { unreachable!() };
+        let mut packet = vec![0x00, 0x00, 0x00, 0x01];
+        packet.extend_from_slice(&source.ip().octets());
+        packet.extend_from_slice(&source.port().to_be_bytes());
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    #[tokio::test]
+    async fn test_relayed_udp_session() {
+        // 模拟 SOCKS5 代理：无认证，UDP ASSOCIATE 返回本地中继端口
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let relay = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_port = relay.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut control, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 3];
+            control.read_exact(&mut buf).await.unwrap();
+            control.write_all(&[0x05, 0x00]).await.unwrap();
+            let mut request = [0u8; 10];
+            control.read_exact(&mut request).await.unwrap();
+            let mut reply = vec![0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1];
+            reply.extend_from_slice(&relay_port.to_be_bytes());
+            control.write_all(&reply).await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let proxy = crate::proxy::Proxy::parse(&format!("socks5://{}", proxy_addr)).unwrap();
+        let target: SocketAddr = "192.0.2.53:53".parse().unwrap();
+        let session = RelayedUdpSession::new(proxy.udp_associate().await.unwrap(), target, "test");
+
+        tokio::spawn(async move {
+            for _ in 0..2 {
+                let mut buf = vec![0u8; 4096];
+                let (len, client) = relay.recv_from(&mut buf).await.unwrap();
+                let query = Message::from_vec(&buf[10..len]).unwrap();
+                let mut response = query.clone();
+                response.set_message_type(hickory_proto::op::MessageType::Response);
+                let data = response.to_vec().unwrap();
+                // 来源地址不符的数据报与消息 ID 不符的响应均被丢弃
+                let spoofed: SocketAddr = "198.51.100.1:53".parse().unwrap();
+                relay.send_to(&socks5_datagram(spoofed, &data), client).await.unwrap();
+                let mut unknown = response.clone();
+                unknown.set_id(query.id().wrapping_add(1));
+                relay.send_to(&socks5_datagram(target, &unknown.to_vec().unwrap()), client).await.unwrap();
+                relay.send_to(&socks5_datagram(target, &data), client).await.unwrap();
+            }
+        });
+
+        // 同一关联上依次完成多个查询
+        for (id, name) in [(11, "a.example."), (12, "b.example.")] {
+            let response = session.query(&request(id, name), Duration::from_secs(5)).await.unwrap();
+            assert_eq!(response.id(), id);
+            assert_eq!(response.queries()[0].name().to_ascii(), name);
+        }
+        assert!(!session.is_closed());
+    }
+}
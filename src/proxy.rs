@@ -0,0 +1,453 @@
+use anyhow::Result;
+use std::io::IoSliceMut;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpStream, UdpSocket};
+use tracing::debug;
+
+/// HTTP CONNECT 响应头最大长度
+const MAX_CONNECT_RESPONSE_LEN: usize = 8192;
+
+/// SOCKS5 UDP 请求头最大长度（RSV + FRAG + ATYP + 域名 + 端口）
+pub const MAX_SOCKS5_UDP_HEADER_LEN: usize = 4 + 1 + 255 + 2;
+
+/// 代理类型
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyKind {
+    /// SOCKS5（`socks5://`、`socks5h://`、`socks5+local://`）
+    Socks5,
+    /// HTTP CONNECT（`http://`）
+    Http,
+}
+
+/// 上游代理（解析自 `UpstreamList.proxy`）
+///
+/// 支持的格式：
+/// - `socks5://[user:pass@]host:port`、`socks5h://[user:pass@]host:port`：目标域名交由代理解析
+/// - `socks5+local://[user:pass@]host:port`：目标域名在本地解析（bootstrap 或系统 DNS）
+/// - `http://[user:pass@]host:port`：HTTP CONNECT，目标域名交由代理解析
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proxy {
+    kind: ProxyKind,
+    /// 代理服务器地址（host:port）
+    addr: String,
+    /// 认证信息（用户名, 密码）
+    auth: Option<(String, String)>,
+    /// 是否由代理解析目标域名
+    remote_dns: bool,
+}
+
+impl Proxy {
+    /// 解析代理 URL
+    pub fn parse(url: &str) -> Result<Self> {
+        let (scheme, rest) = url.split_once("://")
+            .ok_or_else(|| anyhow::anyhow!("无效的代理地址（缺少协议）: {}", url))?;
+        let (kind, remote_dns, default_port) = match scheme.to_ascii_lowercase().as_str() {
+            "socks5" | "socks" | "socks5h" => (ProxyKind::Socks5, true, 1080),
+            "socks5+local" => (ProxyKind::Socks5, false, 1080),
+            "http" => (ProxyKind::Http, true, 80),
+            _ => anyhow::bail!("不支持的代理协议 '{}': {}", scheme, url),
+        };
+
+        let rest = rest.trim_end_matches('/');
+        let (auth, host_port) = match rest.rsplit_once('@') {
+            Some((userinfo, host_port)) => {
+                let (user, pass) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+                (Some((user.to_string(), pass.to_string())), host_port)
+            }
+            None => (None, rest),
+        };
+        if host_port.is_empty() {
+            anyhow::bail!("无效的代理地址（缺少主机）: {}", url);
+        }
+
+        // 未指定端口时使用协议默认端口（注意 IPv6 地址中的冒号）
+        let has_port = match host_port.rsplit_once(':') {
+            Some((host, port)) => (!host.contains(':') || host.ends_with(']')) && port.parse::<u16>().is_ok(),
+            None => false,
+        };
+        let addr = if has_port {
+            host_port.to_string()
+        } else {
+            format!("{}:{}", host_port, default_port)
+        };
+
+        Ok(Self { kind, addr, auth, remote_dns })
+    }
+
+    /// 是否由代理解析目标域名（此时无需 bootstrap）
+    pub fn remote_dns(&self) -> bool {
+        self.remote_dns
+    }
+
+    /// 通过代理建立到目标的 TCP 连接
+    ///
+    /// `host` 可以是域名或 IP；域名在 `remote_dns` 为 false 时先在本地通过系统 DNS 解析
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        debug!("通过代理 {} ({:?}) 连接到 {}:{}", self.addr, self.kind, host, port);
+        match self.kind {
+            ProxyKind::Socks5 => self.connect_socks5(host, port).await,
+            ProxyKind::Http => self.connect_http(host, port).await,
+        }
+    }
+
+    /// 建立 SOCKS5 UDP 关联（UDP ASSOCIATE），用于 UDP / DoQ 上游
+    ///
+    /// tokio-socks 未提供 UDP ASSOCIATE，这里按 RFC 1928 完成握手；HTTP 代理不支持 UDP
+    pub async fn udp_associate(&self) -> Result<UdpAssociation> {
+        if self.kind != ProxyKind::Socks5 {
+            anyhow::bail!("HTTP 代理 {} 不支持 UDP", self.addr);
+        }
+
+        let mut control = TcpStream::connect(&self.addr).await
+            .map_err(|e| anyhow::anyhow!("SOCKS5 代理 {} 连接失败: {}", self.addr, e))?;
+        self.socks5_authenticate(&mut control).await?;
+
+        // 客户端地址未知（可能经过 NAT），按 RFC 1928 填全零
+        control.write_all(&[0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
+        let mut reply = [0u8; 4];
+        control.read_exact(&mut reply).await?;
+        if reply[0] != 0x05 || reply[1] != 0x00 {
+            anyhow::bail!("SOCKS5 代理 {} 拒绝 UDP ASSOCIATE (REP={})", self.addr, reply[1]);
+        }
+        let relay_ip = match reply[3] {
+            0x01 => {
+                let mut octets = [0u8; 4];
+                control.read_exact(&mut octets).await?;
+                IpAddr::from(octets)
+            }
+            0x04 => {
+                let mut octets = [0u8; 16];
+                control.read_exact(&mut octets).await?;
+                IpAddr::from(octets)
+            }
+            atyp => anyhow::bail!("SOCKS5 代理 {} 返回了不支持的中继地址类型 {}", self.addr, atyp),
+        };
+        let relay_port = control.read_u16().await?;
+
+        // 中继地址为全零时使用代理服务器地址
+        let relay_ip = if relay_ip.is_unspecified() { control.peer_addr()?.ip() } else { relay_ip };
+        let relay = SocketAddr::new(relay_ip, relay_port);
+
+        let bind_addr: SocketAddr = match relay {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(relay).await?;
+        debug!("SOCKS5 代理 {} 已建立 UDP 关联，中继地址: {}", self.addr, relay);
+
+        Ok(UdpAssociation { control, socket, recv_buf: Mutex::new(Vec::new()) })
+    }
+
+    /// SOCKS5 方法协商与用户名/密码认证（RFC 1929）
+    async fn socks5_authenticate(&self, stream: &mut TcpStream) -> Result<()> {
+        match &self.auth {
+            Some(_) => stream.write_all(&[0x05, 0x02, 0x00, 0x02]).await?,
+            None => stream.write_all(&[0x05, 0x01, 0x00]).await?,
+        }
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).await?;
+
+        match (choice, &self.auth) {
+            ([0x05, 0x00], _) => Ok(()),
+            ([0x05, 0x02], Some((user, pass))) => {
+                if user.len() > 255 || pass.len() > 255 {
+                    anyhow::bail!("SOCKS5 用户名或密码过长");
+                }
+                let mut request = vec![0x01, user.len() as u8];
+                request.extend_from_slice(user.as_bytes());
+                request.push(pass.len() as u8);
+                request.extend_from_slice(pass.as_bytes());
+                stream.write_all(&request).await?;
+
+                let mut status = [0u8; 2];
+                stream.read_exact(&mut status).await?;
+                if status[1] != 0x00 {
+                    anyhow::bail!("SOCKS5 代理 {} 认证失败", self.addr);
+                }
+                Ok(())
+            }
+            _ => anyhow::bail!("SOCKS5 代理 {} 不接受可用的认证方式", self.addr),
+        }
+    }
+
+    async fn connect_socks5(&self, host: &str, port: u16) -> Result<TcpStream> {
+        use tokio_socks::tcp::Socks5Stream;
+
+        let target = if self.remote_dns || host.parse::<std::net::IpAddr>().is_ok() {
+            host.to_string()
+        } else {
+            tokio::net::lookup_host((host, port)).await?
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("无法解析地址: {}", host))?
+                .ip()
+                .to_string()
+        };
+
+        let stream = match &self.auth {
+            Some((user, pass)) => {
+                Socks5Stream::connect_with_password(self.addr.as_str(), (target.as_str(), port), user, pass).await
+            }
+            None => Socks5Stream::connect(self.addr.as_str(), (target.as_str(), port)).await,
+        }
+        .map_err(|e| anyhow::anyhow!("SOCKS5 代理 {} 连接失败: {}", self.addr, e))?;
+
+        Ok(stream.into_inner())
+    }
+
+    async fn connect_http(&self, host: &str, port: u16) -> Result<TcpStream> {
+        use base64::engine::general_purpose::STANDARD;
+        use base64::Engine;
+
+        let mut stream = TcpStream::connect(&self.addr).await
+            .map_err(|e| anyhow::anyhow!("HTTP 代理 {} 连接失败: {}", self.addr, e))?;
+
+        let authority = if host.contains(':') {
+            format!("[{}]:{}", host, port)
+        } else {
+            format!("{}:{}", host, port)
+        };
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+        if let Some((user, pass)) = &self.auth {
+            let credentials = STANDARD.encode(format!("{}:{}", user, pass));
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // 逐字节读取响应头，避免读走隧道中的后续数据
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() >= MAX_CONNECT_RESPONSE_LEN {
+                anyhow::bail!("HTTP 代理 {} 响应头过长", self.addr);
+            }
+            let byte = stream.read_u8().await
+                .map_err(|e| anyhow::anyhow!("HTTP 代理 {} 响应读取失败: {}", self.addr, e))?;
+            response.push(byte);
+        }
+
+        let status_line = String::from_utf8_lossy(&response);
+        let status_line = status_line.lines().next().unwrap_or_default();
+        let status = status_line.split_whitespace().nth(1).and_then(|code| code.parse::<u16>().ok());
+        match status {
+            Some(200..=299) => Ok(stream),
+            _ => anyhow::bail!("HTTP 代理 {} 拒绝 CONNECT {}: {}", self.addr, authority, status_line),
+        }
+    }
+}
+
+/// SOCKS5 UDP 关联
+///
+/// 控制连接在关联存续期间保持打开，关闭后代理释放中继端口；
+/// 数据报按 RFC 1928 §7 添加目标地址头后发往中继地址
+#[derive(Debug)]
+pub struct UdpAssociation {
+    control: TcpStream,
+    /// 已连接到中继地址的 UDP 套接字
+    socket: UdpSocket,
+    /// 接收缓冲区（含 SOCKS5 请求头）
+    recv_buf: Mutex<Vec<u8>>,
+}
+
+impl UdpAssociation {
+    /// 经中继向目标发送数据报
+    pub async fn send_to(&self, data: &[u8], target: SocketAddr) -> Result<()> {
+        self.socket.send(&encode_udp_datagram(data, target)).await?;
+        Ok(())
+    }
+
+    /// 接收经中继转发的数据报，返回来源地址与数据
+    ///
+    /// `packet` 为接收缓冲区，需为数据预留 `MAX_SOCKS5_UDP_HEADER_LEN` 字节的请求头空间
+    pub async fn recv_from<'a>(&self, packet: &'a mut [u8]) -> Result<(SocketAddr, &'a [u8])> {
+        let (source, start, end) = loop {
+            let len = self.socket.recv(packet).await?;
+            match decode_udp_datagram(&packet[..len]) {
+                Some((source, payload)) => break (source, len - payload.len(), len),
+                None => debug!("丢弃无效的 SOCKS5 UDP 数据报 ({} 字节)", len),
+            }
+        };
+        Ok((source, &packet[start..end]))
+    }
+
+    /// 等待控制连接关闭（代理关闭控制连接后中继端口随之释放，关联失效）
+    pub async fn closed(&self) {
+        let mut buf = [0u8; 64];
+        loop {
+            if self.control.readable().await.is_err() {
+                return;
+            }
+            match self.control.try_read(&mut buf) {
+                Ok(0) => return,
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+/// 供 QUIC 端点使用：数据报经 SOCKS5 UDP 中继收发
+impl quinn::AsyncUdpSocket for UdpAssociation {
+    fn poll_send(
+        &self,
+        _state: &quinn::udp::UdpState,
+        cx: &mut Context,
+        transmits: &[quinn::udp::Transmit],
+    ) -> Poll<std::io::Result<usize>> {
+        for (sent, transmit) in transmits.iter().enumerate() {
+            // GSO 批量发送的多个数据报需逐个封装
+            let segment_size = transmit.segment_size.unwrap_or(transmit.contents.len()).max(1);
+            for (index, segment) in transmit.contents.chunks(segment_size).enumerate() {
+                match self.socket.poll_send(cx, &encode_udp_datagram(segment, transmit.destination)) {
+                    Poll::Pending if sent == 0 && index == 0 => return Poll::Pending,
+                    // 本次 transmit 尚未发出，只报告之前已发出的 transmit
+                    Poll::Pending if index == 0 => return Poll::Ready(Ok(sent)),
+                    // 本次 transmit 已发出部分数据报，剩余部分视为丢包由 QUIC 重传
+                    Poll::Pending => return Poll::Ready(Ok(sent + 1)),
+                    Poll::Ready(Err(e)) => debug!("SOCKS5 UDP 发送失败: {}", e),
+                    Poll::Ready(Ok(_)) => {}
+                }
+            }
+        }
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [quinn::udp::RecvMeta],
+    ) -> Poll<std::io::Result<usize>> {
+        let (Some(buf), Some(meta)) = (bufs.first_mut(), meta.first_mut()) else {
+            return Poll::Ready(Ok(0));
+        };
+
+        let mut packet = self.recv_buf.lock().unwrap();
+        packet.resize(buf.len() + MAX_SOCKS5_UDP_HEADER_LEN, 0);
+        loop {
+            let mut read_buf = ReadBuf::new(&mut packet);
+            std::task::ready!(self.socket.poll_recv(cx, &mut read_buf))?;
+            let Some((source, payload)) = decode_udp_datagram(read_buf.filled()) else {
+                continue;
+            };
+
+            let len = payload.len().min(buf.len());
+            buf[..len].copy_from_slice(&payload[..len]);
+            *meta = quinn::udp::RecvMeta { addr: source, len, stride: len, ecn: None, dst_ip: None };
+            return Poll::Ready(Ok(1));
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+/// 封装 SOCKS5 UDP 数据报：RSV(2) FRAG(1) ATYP(1) DST.ADDR DST.PORT(2) DATA
+fn encode_udp_datagram(data: &[u8], target: SocketAddr) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(data.len() + 22);
+    packet.extend_from_slice(&[0x00, 0x00, 0x00]);
+    match target.ip() {
+        IpAddr::V4(ip) => {
+            packet.push(0x01);
+            packet.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            packet.push(0x04);
+            packet.extend_from_slice(&ip.octets());
+        }
+    }
+    packet.extend_from_slice(&target.port().to_be_bytes());
+    packet.extend_from_slice(data);
+    packet
+}
+
+/// 解析 SOCKS5 UDP 数据报，返回来源地址与数据；分片或格式无效时返回 None
+fn decode_udp_datagram(packet: &[u8]) -> Option<(SocketAddr, &[u8])> {
+    let (header, rest) = packet.split_first_chunk::<4>()?;
+    if header[2] != 0x00 {
+        return None;
+    }
+    let (ip, rest): (IpAddr, &[u8]) = match header[3] {
+        0x01 => {
+            let (octets, rest) = rest.split_first_chunk::<4>()?;
+            (IpAddr::from(*octets), rest)
+        }
+        0x04 => {
+            let (octets, rest) = rest.split_first_chunk::<16>()?;
+            (IpAddr::from(*octets), rest)
+        }
+        _ => return None,
+    };
+    let (port, data) = rest.split_first_chunk::<2>()?;
+    Some((SocketAddr::new(ip, u16::from_be_bytes(*port)), data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proxy() {
+        let proxy = Proxy::parse("socks5://127.0.0.1:7891").unwrap();
+        assert_eq!(proxy.kind, ProxyKind::Socks5);
+        assert_eq!(proxy.addr, "127.0.0.1:7891");
+        assert!(proxy.remote_dns());
+
+        let proxy = Proxy::parse("socks5+local://127.0.0.1:7891").unwrap();
+        assert_eq!(proxy.kind, ProxyKind::Socks5);
+        assert!(!proxy.remote_dns());
+
+        let proxy = Proxy::parse("socks5h://user:p@ss@proxy.lan").unwrap();
+        assert_eq!(proxy.addr, "proxy.lan:1080");
+        assert_eq!(proxy.auth, Some(("user".to_string(), "p@ss".to_string())));
+        assert!(proxy.remote_dns());
+
+        let proxy = Proxy::parse("http://[::1]:8080/").unwrap();
+        assert_eq!(proxy.kind, ProxyKind::Http);
+        assert_eq!(proxy.addr, "[::1]:8080");
+        assert!(proxy.remote_dns());
+
+        assert!(Proxy::parse("127.0.0.1:7890").is_err());
+        assert!(Proxy::parse("https://127.0.0.1:7890").is_err());
+    }
+
+    #[test]
+    fn test_udp_datagram_roundtrip() {
+        for target in ["8.8.8.8:53", "[2001:4860:4860::8888]:853"] {
+            let target: SocketAddr = target.parse().unwrap();
+            let packet = encode_udp_datagram(b"query", target);
+            assert_eq!(decode_udp_datagram(&packet), Some((target, &b"query"[..])));
+        }
+
+        // 分片数据报被丢弃
+        let mut packet = encode_udp_datagram(b"query", "8.8.8.8:53".parse().unwrap());
+        packet[2] = 0x01;
+        assert_eq!(decode_udp_datagram(&packet), None);
+    }
+
+    #[tokio::test]
+    async fn test_http_connect() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 1024];
+            let len = stream.read(&mut buf).await.unwrap();
+            let request = String::from_utf8_lossy(&buf[..len]).to_string();
+            assert!(request.starts_with("CONNECT dns.google:853 HTTP/1.1\r\n"));
+            assert!(request.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+            stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\nhello").await.unwrap();
+        });
+
+        let proxy = Proxy::parse(&format!("http://user:pass@{}", addr)).unwrap();
+        let mut stream = proxy.connect("dns.google", 853).await.unwrap();
+        let mut tunneled = [0u8; 5];
+        stream.read_exact(&mut tunneled).await.unwrap();
+        assert_eq!(&tunneled, b"hello");
+    }
+}
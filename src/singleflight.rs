@@ -0,0 +1,123 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Mutex;
+use tokio::sync::watch;
+
+/// 进行中的调用结果（错误以文本形式共享给等待方）
+type Outcome<T> = Option<Result<T, String>>;
+
+/// 合并相同键的并发调用
+///
+/// 同一时刻相同键只有第一个调用方（leader）真正执行，其余调用方等待并共享其结果；
+/// leader 被取消时等待方各自执行
+pub struct Singleflight<K, T> {
+    inflight: Mutex<HashMap<K, watch::Receiver<Outcome<T>>>>,
+}
+
+impl<K: Eq + Hash + Clone, T: Clone> Singleflight<K, T> {
+    pub fn new() -> Self {
+        Self { inflight: Mutex::new(HashMap::new()) }
+    }
+
+    /// 执行 `f`，相同键已有进行中的调用时等待其结果
+    ///
+    /// 返回值中的布尔值表示结果是否来自其他调用方
+    pub async fn run<F, Fut>(&self, key: K, f: F) -> (Result<T>, bool)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let waiting = {
+            let mut inflight = self.inflight.lock().unwrap();
+            match inflight.get(&key) {
+                Some(receiver) => Err(receiver.clone()),
+                None => {
+                    let (sender, receiver) = watch::channel(None);
+                    inflight.insert(key.clone(), receiver);
+                    Ok(sender)
+                }
+            }
+        };
+
+        let sender = match waiting {
+            Ok(sender) => sender,
+            Err(mut receiver) => {
+                if let Ok(outcome) = receiver.wait_for(Option::is_some).await {
+                    let shared = outcome.clone().expect("已等待到结果");
+                    return (shared.map_err(|e| anyhow::anyhow!(e)), true);
+                }
+                // leader 未完成即被取消，自行执行
+                return (f().await, false);
+            }
+        };
+
+        let guard = InflightGuard { inflight: &self.inflight, key };
+        let result = f().await;
+        drop(guard);
+        sender.send_replace(Some(result.as_ref().map(T::clone).map_err(|e| e.to_string())));
+        (result, false)
+    }
+}
+
+/// leader 完成或被取消时移除进行中的记录
+struct InflightGuard<'a, K: Eq + Hash, T> {
+    inflight: &'a Mutex<HashMap<K, watch::Receiver<Outcome<T>>>>,
+    key: K,
+}
+
+impl<K: Eq + Hash, T> Drop for InflightGuard<'_, K, T> {
+    fn drop(&mut self) {
+        self.inflight.lock().unwrap().remove(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let flight = Singleflight::<&str, u32>::new();
+        let calls = AtomicUsize::new(0);
+        let call = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(7)
+        };
+
+        let (a, b, c) = tokio::join!(flight.run("k", call), flight.run("k", call), flight.run("other", call));
+        assert_eq!((a.0.unwrap(), a.1), (7, false));
+        assert_eq!((b.0.unwrap(), b.1), (7, true));
+        assert_eq!((c.0.unwrap(), c.1), (7, false));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(flight.inflight.lock().unwrap().is_empty());
+
+        // 已完成的调用不再合并
+        let (d, shared) = flight.run("k", call).await;
+        assert_eq!((d.unwrap(), shared), (7, false));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_leader_cancelled() {
+        let flight = Singleflight::<&str, u32>::new();
+        let leader = flight.run("k", || async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(1)
+        });
+        let follower = flight.run("k", || async { Ok(2) });
+
+        // leader 超时被取消后，等待方自行执行
+        let (leader, follower) = tokio::join!(
+            tokio::time::timeout(Duration::from_millis(20), leader),
+            follower,
+        );
+        assert!(leader.is_err());
+        assert_eq!((follower.0.unwrap(), follower.1), (2, false));
+        assert!(flight.inflight.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,252 @@
+use anyhow::Result;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::config::UpstreamTlsConfig;
+
+/// 从 PEM 文件加载证书链
+pub fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+    let file = File::open(path)
+        .map_err(|e| anyhow::anyhow!("无法打开证书文件 '{}': {}", path, e))?;
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|e| anyhow::anyhow!("解析证书文件 '{}' 失败: {}", path, e))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    if certs.is_empty() {
+        anyhow::bail!("证书文件 '{}' 中未找到证书", path);
+    }
+    Ok(certs)
+}
+
+/// 从 PEM 文件加载私钥（支持 PKCS#8 / PKCS#1 / SEC1）
+pub fn load_private_key(path: &str) -> Result<PrivateKey> {
+    use rustls_pemfile::Item;
+
+    let file = File::open(path)
+        .map_err(|e| anyhow::anyhow!("无法打开私钥文件 '{}': {}", path, e))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(|e| anyhow::anyhow!("解析私钥文件 '{}' 失败: {}", path, e))?;
+
+    items.into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow::anyhow!("私钥文件 '{}' 中未找到私钥", path))
+}
+
+/// 创建服务端 TLS 配置
+///
+/// `alpn` 为服务端支持的 ALPN 协议列表（如 DoT 使用 `dot`）
+pub fn server_config(cert_path: &str, key_path: &str, alpn: &[&[u8]]) -> Result<Arc<ServerConfig>> {
+    let config = build_server_config(cert_path, key_path, alpn, rustls::DEFAULT_VERSIONS)?;
+    Ok(Arc::new(config))
+}
+
+/// 创建 QUIC 服务端 TLS 配置（仅 TLS 1.3，允许 0-RTT）
+pub fn quic_server_config(cert_path: &str, key_path: &str, alpn: &[&[u8]]) -> Result<Arc<ServerConfig>> {
+    let mut config = build_server_config(cert_path, key_path, alpn, &[&rustls::version::TLS13])?;
+    config.max_early_data_size = u32::MAX;
+    Ok(Arc::new(config))
+}
+
+fn build_server_config(
+    cert_path: &str,
+    key_path: &str,
+    alpn: &[&[u8]],
+    versions: &[&'static rustls::SupportedProtocolVersion],
+) -> Result<ServerConfig> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+
+    let mut config = ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)
+        .map_err(|e| anyhow::anyhow!("TLS 协议版本配置无效: {}", e))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| anyhow::anyhow!("证书与私钥不匹配: {}", e))?;
+    config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+
+    Ok(config)
+}
+
+/// 内置根证书（webpki-roots）
+pub fn root_store() -> RootCertStore {
+    let mut root_store = RootCertStore::empty();
+    root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    root_store
+}
+
+/// 创建客户端 TLS 配置（按上游 TLS 选项设置根证书、指纹校验与客户端证书）
+///
+/// 配置应被复用：rustls 在配置内缓存会话，复用同一配置的连接可进行 TLS 会话恢复
+pub fn client_config(alpn: &[&[u8]], options: &UpstreamTlsConfig) -> Result<ClientConfig> {
+    let roots = match &options.ca {
+        Some(path) => {
+            let mut store = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                store.add(&cert)
+                    .map_err(|e| anyhow::anyhow!("CA 证书文件 '{}' 无效: {}", path, e))?;
+            }
+            store
+        }
+        None => root_store(),
+    };
+
+    let verifier: Arc<dyn ServerCertVerifier> = if options.insecure_skip_verify || !options.spki_pins.is_empty() {
+        Arc::new(PinningVerifier {
+            webpki: (!options.insecure_skip_verify).then(|| WebPkiVerifier::new(roots, None)),
+            pins: options.spki_pins.iter().map(|pin| parse_spki_pin(pin)).collect::<Result<_>>()?,
+        })
+    } else {
+        Arc::new(WebPkiVerifier::new(roots, None))
+    };
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(verifier);
+
+    let mut config = match (&options.client_cert, &options.client_key) {
+        (Some(cert_path), Some(key_path)) => builder
+            .with_client_auth_cert(load_certs(cert_path)?, load_private_key(key_path)?)
+            .map_err(|e| anyhow::anyhow!("客户端证书与私钥不匹配: {}", e))?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => anyhow::bail!("client_cert 与 client_key 必须同时配置"),
+    };
+    config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+    Ok(config)
+}
+
+/// 解析 SPKI 指纹（base64 编码的 SHA-256，可带 `sha256/` 前缀）
+fn parse_spki_pin(pin: &str) -> Result<[u8; 32]> {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+
+    let encoded = pin.trim().trim_start_matches("sha256/");
+    STANDARD.decode(encoded)
+        .ok()
+        .and_then(|digest| <[u8; 32]>::try_from(digest).ok())
+        .ok_or_else(|| anyhow::anyhow!("无效的 SPKI 指纹 '{}'（应为 base64 编码的 SHA-256）", pin))
+}
+
+/// 证书校验器：可选的证书链校验 + SPKI 指纹校验
+struct PinningVerifier {
+    /// 证书链与名称校验，None 表示跳过（insecure_skip_verify）
+    webpki: Option<WebPkiVerifier>,
+    /// 允许的 SPKI SHA-256 指纹，为空表示不校验指纹
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)?;
+        }
+        if self.pins.is_empty() {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        let matched = std::iter::once(end_entity)
+            .chain(intermediates)
+            .filter_map(|cert| spki_der(&cert.0))
+            .any(|spki| {
+                let digest = ring::digest::digest(&ring::digest::SHA256, spki);
+                self.pins.iter().any(|pin| pin[..] == *digest.as_ref())
+            });
+        if matched {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General("证书公钥指纹 (SPKI) 不匹配".to_string()))
+        }
+    }
+}
+
+/// 从 DER 编码的 X.509 证书中取出 SubjectPublicKeyInfo（含标签与长度）
+fn spki_der(cert: &[u8]) -> Option<&[u8]> {
+    let (_, certificate, _) = der_element(cert)?;
+    let (_, tbs, _) = der_element(certificate)?;
+
+    // TBSCertificate: [0] version（可选）, serialNumber, signature, issuer, validity, subject, subjectPublicKeyInfo
+    let mut rest = tbs;
+    if rest.first() == Some(&0xa0) {
+        rest = der_element(rest)?.2;
+    }
+    for _ in 0..5 {
+        rest = der_element(rest)?.2;
+    }
+    let (tag, _, after) = der_element(rest)?;
+    (tag == 0x30).then(|| &rest[..rest.len() - after.len()])
+}
+
+/// 读取一个 DER 元素，返回 (标签, 内容, 剩余数据)
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let len = rest[..count].iter().fold(0usize, |len, &b| (len << 8) | b as usize);
+        (len, &rest[count..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spki_der() {
+        // 最小化的证书结构：仅用于定位 SubjectPublicKeyInfo
+        let spki = [0x30, 0x03, 0x02, 0x01, 0x07];
+        let mut tbs = vec![0xa0, 0x03, 0x02, 0x01, 0x02, 0x02, 0x01, 0x01];
+        tbs.extend_from_slice(&[0x30, 0x00, 0x30, 0x00, 0x30, 0x00, 0x30, 0x00]);
+        tbs.extend_from_slice(&spki);
+        tbs.extend_from_slice(&[0xa3, 0x00]);
+
+        let mut tbs_der = vec![0x30, 0x81, tbs.len() as u8];
+        tbs_der.extend_from_slice(&tbs);
+        let mut cert = vec![0x30, tbs_der.len() as u8 + 4];
+        cert.extend_from_slice(&tbs_der);
+        cert.extend_from_slice(&[0x30, 0x00, 0x03, 0x00]);
+
+        assert_eq!(spki_der(&cert), Some(&spki[..]));
+        assert_eq!(spki_der(&cert[..cert.len() - 10]), None);
+    }
+
+    #[test]
+    fn test_parse_spki_pin() {
+        let pin = "sha256/47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+        assert!(parse_spki_pin(pin).is_ok());
+        assert!(parse_spki_pin("not-a-pin").is_err());
+        assert!(parse_spki_pin("AAAA").is_err());
+    }
+}
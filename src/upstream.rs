@@ -0,0 +1,254 @@
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::config::{UpstreamList, UpstreamStrategy};
+
+/// 延迟滑动平均的新样本权重
+const LATENCY_EWMA_WEIGHT: f64 = 0.3;
+
+/// 上游地址健康状态
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HealthStatus {
+    /// 最近一次查询成功
+    Healthy,
+    /// 出现连续失败，但未达到熔断阈值
+    Degraded,
+    /// 已熔断，选择地址时跳过，直到健康检查探测成功
+    Down,
+}
+
+/// 单个上游地址的统计
+#[derive(Default)]
+struct AddrStats {
+    /// 延迟滑动平均（毫秒），未测量时为 None
+    latency_ms: Option<f64>,
+    /// 连续失败次数
+    failures: u32,
+}
+
+/// 上游地址运行时状态（延迟统计、健康状态与轮询计数）
+///
+/// 统计按地址记录，同一地址出现在多个上游列表中时共享
+pub struct UpstreamState {
+    /// 地址 -> 统计
+    stats: RwLock<HashMap<String, AddrStats>>,
+    /// 上游列表（地址拼接） -> 下一次轮询起点
+    round_robin: Mutex<HashMap<String, usize>>,
+    /// 连续失败达到该次数后熔断，None 表示不熔断
+    failure_threshold: Option<u32>,
+}
+
+impl UpstreamState {
+    pub fn new(failure_threshold: Option<u32>) -> Self {
+        Self {
+            stats: RwLock::new(HashMap::new()),
+            round_robin: Mutex::new(HashMap::new()),
+            failure_threshold,
+        }
+    }
+
+    /// 查询地址的健康状态
+    pub fn status(&self, addr: &str) -> HealthStatus {
+        let stats = self.stats.read().unwrap();
+        self.status_of(stats.get(addr).map_or(0, |s| s.failures))
+    }
+
+    fn status_of(&self, failures: u32) -> HealthStatus {
+        match self.failure_threshold {
+            Some(threshold) if failures >= threshold => HealthStatus::Down,
+            _ if failures > 0 => HealthStatus::Degraded,
+            _ => HealthStatus::Healthy,
+        }
+    }
+
+    /// 按上游策略给出本次查询尝试地址的顺序，已熔断的地址被跳过
+    ///
+    /// 除 `parallel` 外，首个地址失败后按返回顺序依次尝试其余地址；
+    /// 所有地址均已熔断时返回空列表
+    pub fn order<'a>(&self, upstream_list: &'a UpstreamList) -> Vec<&'a str> {
+        let mut addrs: Vec<&str> = upstream_list.addr.iter()
+            .map(String::as_str)
+            .filter(|addr| self.status(addr) != HealthStatus::Down)
+            .collect();
+        if addrs.len() <= 1 {
+            return addrs;
+        }
+
+        match upstream_list.strategy {
+            UpstreamStrategy::Failover | UpstreamStrategy::Parallel => {}
+            UpstreamStrategy::RoundRobin => {
+                let key = upstream_list.addr.join(",");
+                let mut counters = self.round_robin.lock().unwrap();
+                let next = counters.entry(key).or_insert(0);
+                let start = *next % addrs.len();
+                addrs.rotate_left(start);
+                *next = next.wrapping_add(1);
+            }
+            UpstreamStrategy::Random => {
+                addrs.shuffle(&mut rand::thread_rng());
+            }
+            UpstreamStrategy::Fastest => {
+                self.order_by_latency(&mut addrs);
+            }
+        }
+        addrs
+    }
+
+    /// 按延迟加权排序：未测量的地址优先（用于获取延迟样本），
+    /// 其余按 1/延迟 的权重随机选出首个地址，剩余地址按延迟升序作为备选
+    fn order_by_latency(&self, addrs: &mut [&str]) {
+        let all_stats = self.stats.read().unwrap();
+        let stats: Vec<Option<f64>> = addrs.iter()
+            .map(|addr| all_stats.get(*addr).and_then(|s| s.latency_ms))
+            .collect();
+        drop(all_stats);
+
+        if let Some(unmeasured) = stats.iter().position(Option::is_none) {
+            addrs.swap(0, unmeasured);
+            return;
+        }
+
+        let weights: Vec<f64> = stats.iter().map(|ms| 1.0 / ms.unwrap_or(1.0).max(1.0)).collect();
+        let mut pick = rand::thread_rng().gen::<f64>() * weights.iter().sum::<f64>();
+        let mut chosen = weights.len() - 1;
+        for (index, weight) in weights.iter().enumerate() {
+            if pick < *weight {
+                chosen = index;
+                break;
+            }
+            pick -= weight;
+        }
+
+        let mut ranked: Vec<(&str, f64)> = addrs.iter().copied().zip(stats.into_iter().flatten()).collect();
+        let first = ranked.remove(chosen);
+        ranked.sort_by(|a, b| a.1.total_cmp(&b.1));
+        for (slot, (addr, _)) in addrs.iter_mut().zip(std::iter::once(first).chain(ranked)) {
+            *slot = addr;
+        }
+    }
+
+    /// 记录一次成功的查询及其耗时
+    pub fn record_success(&self, addr: &str, elapsed: Duration) {
+        let previous = self.update(addr, elapsed, |failures| *failures = 0);
+        if previous != HealthStatus::Healthy {
+            info!("上游 {} 已恢复正常 (之前: {:?})", addr, previous);
+        }
+    }
+
+    /// 记录一次失败的查询，按惩罚耗时计入延迟；连续失败达到阈值后熔断
+    pub fn record_failure(&self, addr: &str, penalty: Duration) {
+        let mut failures_now = 0;
+        let previous = self.update(addr, penalty, |failures| {
+            *failures = failures.saturating_add(1);
+            failures_now = *failures;
+        });
+
+        match self.status_of(failures_now) {
+            HealthStatus::Down if previous != HealthStatus::Down => {
+                warn!("上游 {} 连续失败 {} 次，已熔断", addr, failures_now);
+            }
+            status => debug!("上游 {} 连续失败 {} 次 ({:?})", addr, failures_now, status),
+        }
+    }
+
+    /// 更新地址统计，返回更新前的健康状态
+    fn update(&self, addr: &str, elapsed: Duration, update_failures: impl FnOnce(&mut u32)) -> HealthStatus {
+        let sample = elapsed.as_secs_f64() * 1000.0;
+        let mut stats = self.stats.write().unwrap();
+        let entry = stats.entry(addr.to_string()).or_default();
+        let previous = self.status_of(entry.failures);
+
+        entry.latency_ms = Some(match entry.latency_ms {
+            Some(avg) => avg * (1.0 - LATENCY_EWMA_WEIGHT) + sample * LATENCY_EWMA_WEIGHT,
+            None => sample,
+        });
+        update_failures(&mut entry.failures);
+        previous
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstream_list(strategy: UpstreamStrategy) -> UpstreamList {
+        UpstreamList {
+            addr: vec!["udp://a:53".to_string(), "udp://b:53".to_string(), "udp://c:53".to_string()],
+            bootstrap: None,
+            proxy: None,
+            cache: None,
+            strategy,
+            doh_method: Default::default(),
+            tls: Default::default(),
+            ip_preference: Default::default(),
+            hosts: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_round_robin_order() {
+        let state = UpstreamState::new(None);
+        let list = upstream_list(UpstreamStrategy::RoundRobin);
+
+        assert_eq!(state.order(&list), vec!["udp://a:53", "udp://b:53", "udp://c:53"]);
+        assert_eq!(state.order(&list), vec!["udp://b:53", "udp://c:53", "udp://a:53"]);
+        assert_eq!(state.order(&list), vec!["udp://c:53", "udp://a:53", "udp://b:53"]);
+        assert_eq!(state.order(&list)[0], "udp://a:53");
+    }
+
+    #[test]
+    fn test_fastest_order() {
+        let state = UpstreamState::new(None);
+        let list = upstream_list(UpstreamStrategy::Fastest);
+
+        // 未测量的地址优先
+        state.record_success("udp://a:53", Duration::from_millis(200));
+        state.record_success("udp://b:53", Duration::from_millis(10));
+        assert_eq!(state.order(&list)[0], "udp://c:53");
+
+        // 全部测量后，备选地址按延迟升序，且首选地址以延迟最低者为主
+        state.record_failure("udp://c:53", Duration::from_millis(5000));
+        let mut fastest_first = 0;
+        for _ in 0..100 {
+            let order = state.order(&list);
+            assert_eq!(order.len(), 3);
+            if order[0] == "udp://b:53" {
+                fastest_first += 1;
+                assert_eq!(order[1..], ["udp://a:53", "udp://c:53"]);
+            }
+        }
+        assert!(fastest_first > 50);
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let state = UpstreamState::new(Some(2));
+        let list = upstream_list(UpstreamStrategy::Failover);
+        let penalty = Duration::from_secs(5);
+
+        state.record_failure("udp://a:53", penalty);
+        assert_eq!(state.status("udp://a:53"), HealthStatus::Degraded);
+        assert_eq!(state.order(&list)[0], "udp://a:53");
+
+        // 达到阈值后熔断并被跳过
+        state.record_failure("udp://a:53", penalty);
+        assert_eq!(state.status("udp://a:53"), HealthStatus::Down);
+        assert_eq!(state.order(&list), vec!["udp://b:53", "udp://c:53"]);
+
+        // 探测成功后恢复
+        state.record_success("udp://a:53", Duration::from_millis(20));
+        assert_eq!(state.status("udp://a:53"), HealthStatus::Healthy);
+        assert_eq!(state.order(&list)[0], "udp://a:53");
+
+        // 所有地址熔断时返回空列表
+        for addr in &list.addr {
+            state.record_failure(addr, penalty);
+            state.record_failure(addr, penalty);
+        }
+        assert!(state.order(&list).is_empty());
+    }
+}